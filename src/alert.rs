@@ -0,0 +1,88 @@
+//! The ALERT# line of the fan controller, raised on fan faults
+
+use std::{
+    error::Error,
+    sync::{Arc, Mutex},
+};
+
+use rppal::gpio::{Gpio, InputPin, Trigger};
+
+/// Called each time the line asserts
+pub type Notify = Box<dyn Fn() + Send>;
+
+/// A line signalling the controller wants its status read
+pub trait AlertLine {
+    /// Start calling `notify` on each assertion of the line
+    fn watch(&mut self, notify: Notify) -> Result<(), Box<dyn Error>>;
+}
+
+/// ALERT# wired to a Raspberry Pi GPIO, active low and open drain
+pub struct GpioAlert {
+    pin: InputPin,
+}
+
+impl GpioAlert {
+    /// Open the GPIO with BCM number `pin`, pulled up as ALERT# is open drain
+    pub fn open(pin: u8) -> Result<Self, Box<dyn Error>> {
+        let pin = Gpio::new()
+            .and_then(|gpio| gpio.get(pin))
+            .map_err(|err| format!("Unable to open GPIO {pin} for ALERT#: {err}"))?;
+        Ok(Self {
+            pin: pin.into_input_pullup(),
+        })
+    }
+}
+
+impl AlertLine for GpioAlert {
+    fn watch(&mut self, notify: Notify) -> Result<(), Box<dyn Error>> {
+        self.pin
+            .set_async_interrupt(Trigger::FallingEdge, move |_| notify())?;
+        Ok(())
+    }
+}
+
+/// An alert line asserted by hand, standing in for the GPIO
+#[derive(Clone, Default)]
+pub struct SimulatedAlert {
+    notify: Arc<Mutex<Option<Notify>>>,
+}
+
+impl SimulatedAlert {
+    /// Assert the line once
+    pub fn trigger(&self) {
+        if let Some(notify) = self.notify.lock().unwrap().as_ref() {
+            notify();
+        }
+    }
+}
+
+impl AlertLine for SimulatedAlert {
+    fn watch(&mut self, notify: Notify) -> Result<(), Box<dyn Error>> {
+        *self.notify.lock().unwrap() = Some(notify);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+
+    #[test]
+    fn simulated_alert_notifies_each_trigger() {
+        let count = Arc::new(AtomicU32::new(0));
+        let mut line = SimulatedAlert::default();
+        let trigger = line.clone();
+        // Nothing watches the line yet
+        trigger.trigger();
+        let counter = count.clone();
+        line.watch(Box::new(move || {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }))
+        .unwrap();
+        trigger.trigger();
+        trigger.trigger();
+        assert_eq!(count.load(Ordering::Relaxed), 2);
+    }
+}
@@ -0,0 +1,264 @@
+//! Case air temperature from I2C sensors: LM75, TMP102 and SHT3x
+
+use std::error::Error;
+
+use rppal::i2c::{I2c, Result};
+use serde::Deserialize;
+use tokio::time::{sleep, Duration};
+
+use crate::bus;
+
+/// Temperature register of the LM75 and TMP102
+const TEMP_REGISTER: u8 = 0x00;
+/// SHT3x single shot measurement, high repeatability, no clock stretching
+const SHT3X_MEASURE: [u8; 2] = [0x24, 0x00];
+/// Longest SHT3x measurement at high repeatability
+const SHT3X_MEASURE_MS: u64 = 16;
+/// Polynomial of the SHT3x CRC-8
+const SHT3X_CRC_POLY: u8 = 0x31;
+/// Temperature of the simulated sensors in degrees Celsius
+const SIMULATED_TEMP: f32 = 30.0;
+
+/// A supported I2C temperature sensor
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Chip {
+    /// LM75 and LM75B, up to 11 bit
+    Lm75,
+    /// TMP102, 12 bit
+    Tmp102,
+    /// SHT30, SHT31 and SHT35, temperature only
+    Sht3x,
+}
+
+impl Chip {
+    /// Address of the sensor with its address pins low
+    pub fn default_address(self) -> u16 {
+        match self {
+            Chip::Lm75 | Chip::Tmp102 => 0x48,
+            Chip::Sht3x => 0x44,
+        }
+    }
+
+    /// Degrees Celsius of a left aligned temperature register
+    fn register_temp(self, raw: [u8; 2]) -> f32 {
+        let raw = i16::from_be_bytes(raw);
+        match self {
+            Chip::Tmp102 => f32::from(raw >> 4) * 0.0625,
+            _ => f32::from(raw >> 5) * 0.125,
+        }
+    }
+}
+
+impl std::fmt::Display for Chip {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Chip::Lm75 => write!(f, "lm75"),
+            Chip::Tmp102 => write!(f, "tmp102"),
+            Chip::Sht3x => write!(f, "sht3x"),
+        }
+    }
+}
+
+/// Raw transfers with a sensor
+pub trait Transfer: Send {
+    /// Write `bytes`, then read `buf` in one transaction
+    fn write_read(&mut self, bytes: &[u8], buf: &mut [u8]) -> Result<()>;
+    /// Write `bytes` alone
+    fn write(&mut self, bytes: &[u8]) -> Result<()>;
+    /// Read `buf` alone
+    fn read(&mut self, buf: &mut [u8]) -> Result<()>;
+}
+
+impl Transfer for I2c {
+    fn write_read(&mut self, bytes: &[u8], buf: &mut [u8]) -> Result<()> {
+        I2c::write_read(self, bytes, buf)
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        I2c::write(self, bytes).map(|_| ())
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<()> {
+        I2c::read(self, buf).map(|_| ())
+    }
+}
+
+/// CRC-8 of the SHT3x words
+fn crc8(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0xFF, |crc, &byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 != 0 {
+                (crc << 1) ^ SHT3X_CRC_POLY
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+/// A sensor answering like the chip, from its registers or its measurement buffer
+pub struct SimulatedSensor {
+    chip: Chip,
+    /// Temperature register, or the SHT3x measurement
+    data: Vec<u8>,
+    /// A measurement was started and can be read
+    measured: bool,
+}
+
+impl SimulatedSensor {
+    /// A `chip` measuring `temp` degrees Celsius
+    pub fn new(chip: Chip, temp: f32) -> Self {
+        let data = match chip {
+            Chip::Lm75 => (((temp / 0.125) as i16) << 5).to_be_bytes().to_vec(),
+            Chip::Tmp102 => (((temp / 0.0625) as i16) << 4).to_be_bytes().to_vec(),
+            Chip::Sht3x => {
+                let raw = (((temp + 45.0) / 175.0) * 65535.0) as u16;
+                // Temperature then 50% humidity, each with its CRC
+                let mut data = raw.to_be_bytes().to_vec();
+                data.push(crc8(&data));
+                data.extend([0x80, 0x00, crc8(&[0x80, 0x00])]);
+                data
+            }
+        };
+        Self {
+            chip,
+            data,
+            measured: false,
+        }
+    }
+}
+
+impl Transfer for SimulatedSensor {
+    fn write_read(&mut self, bytes: &[u8], buf: &mut [u8]) -> Result<()> {
+        if self.chip == Chip::Sht3x || bytes != [TEMP_REGISTER] || buf.len() > self.data.len() {
+            return Err(rppal::i2c::Error::FeatureNotSupported);
+        }
+        buf.copy_from_slice(&self.data[..buf.len()]);
+        Ok(())
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<()> {
+        if self.chip != Chip::Sht3x || bytes != SHT3X_MEASURE {
+            return Err(rppal::i2c::Error::FeatureNotSupported);
+        }
+        self.measured = true;
+        Ok(())
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<()> {
+        // Like the chip, nothing to read without a measurement
+        if !std::mem::take(&mut self.measured) || buf.len() > self.data.len() {
+            return Err(rppal::i2c::Error::FeatureNotSupported);
+        }
+        buf.copy_from_slice(&self.data[..buf.len()]);
+        Ok(())
+    }
+}
+
+/// An I2C temperature sensor
+pub struct AmbientSensor {
+    chip: Chip,
+    bus: u8,
+    address: u16,
+    device: Box<dyn Transfer>,
+}
+
+impl AmbientSensor {
+    /// Open the sensor at `address` in I2C `bus`, or a simulated one
+    pub fn open(
+        chip: Chip,
+        bus: u8,
+        address: u16,
+        simulate: bool,
+    ) -> std::result::Result<Self, Box<dyn Error>> {
+        let device: Box<dyn Transfer> = if simulate {
+            Box::new(SimulatedSensor::new(chip, SIMULATED_TEMP))
+        } else {
+            Box::new(bus::open(bus, address)?)
+        };
+        Ok(Self {
+            chip,
+            bus,
+            address,
+            device,
+        })
+    }
+
+    /// The temperature in degrees Celsius
+    pub async fn read(&mut self) -> std::result::Result<f32, String> {
+        let (bus, address) = (self.bus, self.address);
+        let fail = |err| bus::device_error(bus, address, err).to_string();
+        match self.chip {
+            Chip::Lm75 | Chip::Tmp102 => {
+                let mut raw = [0; 2];
+                self.device
+                    .write_read(&[TEMP_REGISTER], &mut raw)
+                    .map_err(fail)?;
+                Ok(self.chip.register_temp(raw))
+            }
+            Chip::Sht3x => {
+                self.device.write(&SHT3X_MEASURE).map_err(fail)?;
+                sleep(Duration::from_millis(SHT3X_MEASURE_MS)).await;
+                let mut data = [0; 3];
+                self.device.read(&mut data).map_err(fail)?;
+                if crc8(&data[..2]) != data[2] {
+                    return Err(format!(
+                        "Bad {} CRC from address {:#04x} in I2c bus {}",
+                        self.chip, self.address, self.bus
+                    ));
+                }
+                let raw = u16::from_be_bytes([data[0], data[1]]);
+                Ok(-45.0 + 175.0 * f32::from(raw) / 65535.0)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sensor(device: SimulatedSensor) -> AmbientSensor {
+        AmbientSensor {
+            chip: device.chip,
+            bus: 1,
+            address: device.chip.default_address(),
+            device: Box::new(device),
+        }
+    }
+
+    #[test]
+    fn crc8_matches_the_datasheet() {
+        // SHT3x datasheet example
+        assert_eq!(crc8(&[0xBE, 0xEF]), 0x92);
+    }
+
+    #[test]
+    fn register_temp_is_signed() {
+        assert_eq!(Chip::Lm75.register_temp([0xE7, 0x00]), -25.0);
+        assert_eq!(Chip::Lm75.register_temp([0x19, 0x00]), 25.0);
+        assert_eq!(Chip::Tmp102.register_temp([0xF5, 0xF0]), -10.0625);
+        assert_eq!(Chip::Tmp102.register_temp([0x7F, 0xF0]), 127.9375);
+    }
+
+    #[tokio::test]
+    async fn simulated_sensors_read_below_zero() {
+        let mut lm75 = sensor(SimulatedSensor::new(Chip::Lm75, -25.0));
+        assert_eq!(lm75.read().await.unwrap(), -25.0);
+        let mut tmp102 = sensor(SimulatedSensor::new(Chip::Tmp102, -10.0625));
+        assert_eq!(tmp102.read().await.unwrap(), -10.0625);
+    }
+
+    #[tokio::test]
+    async fn sht3x_checks_the_crc() {
+        let mut sht3x = sensor(SimulatedSensor::new(Chip::Sht3x, SIMULATED_TEMP));
+        let temp = sht3x.read().await.unwrap();
+        assert!((temp - SIMULATED_TEMP).abs() < 0.01, "{temp}");
+
+        let mut device = SimulatedSensor::new(Chip::Sht3x, SIMULATED_TEMP);
+        device.data[1] ^= 0x01;
+        let err = sensor(device).read().await.unwrap_err();
+        assert_eq!(err, "Bad sht3x CRC from address 0x44 in I2c bus 1");
+    }
+}
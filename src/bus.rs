@@ -0,0 +1,97 @@
+//! Opening I2C devices with precise diagnostics
+
+use std::{fmt, io::ErrorKind};
+
+use rppal::i2c::{Error, I2c};
+
+/// `ENXIO`, returned when no device acknowledges the address
+const ENXIO: i32 = 6;
+/// `EREMOTEIO`, returned by some bus drivers on a missing acknowledge
+const EREMOTEIO: i32 = 121;
+
+/// Why an I2C device could not be reached
+#[derive(Debug)]
+pub enum BusError {
+    /// The bus device file does not exist
+    BusMissing { bus: u8 },
+    /// The bus exists but cannot be opened
+    BusAccess { bus: u8, err: Error },
+    /// Nothing acknowledges the address on the bus
+    DeviceAbsent { bus: u8, address: u16 },
+    /// Any other error talking to the device
+    Device { bus: u8, address: u16, err: Error },
+}
+
+impl fmt::Display for BusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BusError::BusMissing { bus } => write!(
+                f,
+                "I2c bus {bus} is missing, /dev/i2c-{bus} does not exist. Is dtparam=i2c_vc=on set \
+                 in config.txt?"
+            ),
+            BusError::BusAccess { bus, err } => write!(f, "Unable to open I2c bus {bus}: {err}"),
+            BusError::DeviceAbsent { bus, address } => {
+                write!(
+                    f,
+                    "No device answers at address {address:#04x} in I2c bus {bus}"
+                )
+            }
+            BusError::Device { bus, address, err } => {
+                write!(f, "Error on address {address:#04x} in I2c bus {bus}: {err}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for BusError {}
+
+/// Open `bus` and select the device at `address`
+pub fn open(bus: u8, address: u16) -> Result<I2c, BusError> {
+    let mut i2c = I2c::with_bus(bus).map_err(|err| match err {
+        Error::Io(ref io) if io.kind() == ErrorKind::NotFound => BusError::BusMissing { bus },
+        err => BusError::BusAccess { bus, err },
+    })?;
+    i2c.set_slave_address(address)
+        .map_err(|err| device_error(bus, address, err))?;
+    Ok(i2c)
+}
+
+/// Classify an error from a transfer with the device at `address`
+pub fn device_error(bus: u8, address: u16, err: Error) -> BusError {
+    match err {
+        Error::Io(ref io) if matches!(io.raw_os_error(), Some(ENXIO | EREMOTEIO)) => {
+            BusError::DeviceAbsent { bus, address }
+        }
+        err => BusError::Device { bus, address, err },
+    }
+}
+
+/// The message of a failed `what` with the device at `address` on `bus`, and why
+pub fn bus_error(what: &str, bus: u8, address: u16, err: Error) -> String {
+    format!("Unable to {what}: {}", device_error(bus, address, err))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn os_error(code: i32) -> Error {
+        Error::Io(std::io::Error::from_raw_os_error(code))
+    }
+
+    #[test]
+    fn bus_error_tells_a_missing_device_apart() {
+        for code in [ENXIO, EREMOTEIO] {
+            assert_eq!(
+                bus_error("read fan status", 10, 0x2f, os_error(code)),
+                "Unable to read fan status: No device answers at address 0x2f in I2c bus 10"
+            );
+        }
+        let err = bus_error("read fan status", 10, 0x2f, os_error(5));
+        assert!(
+            err.starts_with("Unable to read fan status: Error on address 0x2f in I2c bus 10: "),
+            "{err}"
+        );
+    }
+}
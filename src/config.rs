@@ -0,0 +1,579 @@
+//! Service configuration, read from a TOML file
+
+use std::{
+    error::Error,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+use crate::{
+    curve::Curve,
+    emc2301::{PwmBase, PwmOutput, PwmPolarity, SpinUp},
+    health::HealthConfig,
+    model::{FanModel, Preset},
+    nvme::NvmeConfig,
+    thermal::{Aggregation, ZoneConfig},
+    w1,
+};
+
+/// Default location of the configuration file
+pub const CONFIG_PATH: &str = "/etc/cm4_fan_control.toml";
+/// Mount point of sysfs
+const SYSFS_ROOT: &str = "/sys";
+/// hwmon name of the in-kernel EMC230x driver
+const HWMON_NAME: &str = "emc2305";
+
+/// Cooling device type of the Raspberry Pi 5 active cooler
+const COOLING_TYPE: &str = "pwm-fan";
+
+/// Number of fans of the largest controller, the EMC2305
+const MAX_CHANNELS: u8 = 5;
+/// Settings applying to every fan, read from `[fan]` only
+const FAN_ONLY_KEYS: [&str; 7] = [
+    "driver",
+    "hwmon_name",
+    "sysfs_root",
+    "take_over",
+    "unknown_chip",
+    "alert_pin",
+    "lock",
+];
+
+/// How the fan speed is driven
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ControlMode {
+    /// Write the duty cycle straight to the Fan Setting register
+    #[default]
+    Direct,
+    /// Let the controller hold a target RPM
+    Rpm,
+}
+
+/// What to do when the controller reports a stalled or failing fan
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FaultPolicy {
+    /// Only log the fault
+    Log,
+    /// Kick the fan at full speed, raise an alarm if it keeps failing
+    #[default]
+    Retry,
+    /// Raise an alarm and keep the fan at full speed
+    Alarm,
+}
+
+/// The output the fans are driven through
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Driver {
+    /// An EMC230x fan controller on the I2C bus
+    #[default]
+    Emc230x,
+    /// A hardware PWM channel of the SoC
+    Pwm,
+    /// Software PWM on any GPIO
+    Gpio,
+    /// The hwmon sysfs attributes of an in-kernel driver
+    Hwmon,
+    /// The states of a thermal cooling device
+    Cooling,
+}
+
+/// What to do when the device on the fan bus is not an EMC230x
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnknownChip {
+    /// Do not start the fan control
+    #[default]
+    Refuse,
+    /// Only write the duty cycle to the Fan Setting register
+    Direct,
+}
+
+/// Spin-up settings, for the controller and the software kick
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpinUpConfig {
+    /// Drive level held during spin-up, percent (30 to 65 in steps of 5)
+    pub level: u8,
+    /// Spin-up duration in ms (250, 500, 1000 or 2000)
+    pub time_ms: u16,
+    /// Update periods at full drive before a drive failure (0, 16, 32 or 64)
+    pub drive_fail_count: u8,
+    /// Drive at 100% for the first quarter of the controller spin-up
+    pub kick: bool,
+    /// In direct mode, drive at `level` for `time_ms` when the fan starts
+    pub software_kick: bool,
+}
+
+impl SpinUpConfig {
+    /// The controller spin-up settings
+    pub fn chip(&self) -> SpinUp {
+        SpinUp {
+            level: self.level,
+            time_ms: self.time_ms,
+            drive_fail_count: self.drive_fail_count,
+            kick: self.kick,
+        }
+    }
+}
+
+impl Default for SpinUpConfig {
+    fn default() -> Self {
+        Self {
+            level: 60,
+            time_ms: 500,
+            drive_fail_count: 0,
+            kick: true,
+            software_kick: true,
+        }
+    }
+}
+
+/// Settings of one fan channel of the controller
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FanConfig {
+    /// Fan channel on the controller, from 1
+    pub channel: u8,
+    /// Temperature input, a sysfs file in millidegrees Celsius, thermal zone 0 when unset
+    pub sensor: Option<PathBuf>,
+    /// Temperature inputs followed instead of `sensor`
+    pub zones: Vec<ZoneConfig>,
+    /// How the zone temperatures combine
+    pub aggregation: Aggregation,
+    /// NVMe drive temperature with its own curve, the fan follows the higher speed
+    pub nvme: Option<NvmeConfig>,
+    /// Fan speed vs temperature curve
+    pub curve: Curve,
+    /// How the fan speed is driven
+    pub mode: ControlMode,
+    /// Fan preset giving the defaults of `pulses_per_rev`, `min_drive` and `max_rpm`
+    pub model: Option<FanModel>,
+    /// Tach pulses per revolution (1 to 4), 0 for a fan without tach
+    pub pulses_per_rev: Option<u8>,
+    /// Fan speed, in RPM, at full drive and the target at full speed in rpm mode
+    pub max_rpm: Option<u32>,
+    /// What to do when the fan stalls or fails to spin
+    pub on_fault: FaultPolicy,
+    /// Spin-up retries before raising an alarm with the retry policy
+    pub spin_up_retries: u32,
+    /// The output driving the fans, read from `[fan]` only
+    pub driver: Driver,
+    /// Hardware PWM channel, 0 or 1, with the pwm driver
+    pub pwm_channel: u8,
+    /// BCM number of the GPIO, with the gpio driver
+    pub gpio_pin: Option<u8>,
+    /// PWM frequency in Hz with the pwm and gpio drivers, the driver default when unset
+    pub pwm_hz: Option<f64>,
+    /// `name` of the hwmon device with the hwmon driver, read from `[fan]` only
+    pub hwmon_name: String,
+    /// Where sysfs is mounted, read from `[fan]` only
+    pub sysfs_root: PathBuf,
+    /// `type` of the cooling device with the cooling driver
+    pub cooling_type: String,
+    /// Switch the thermal zones of the cooling devices to the user_space governor, read from
+    /// `[fan]` only
+    pub take_over: bool,
+    /// What to do when the controller is not an EMC230x, read from `[fan]` only
+    pub unknown_chip: UnknownChip,
+    /// BCM number of the GPIO wired to ALERT#, read from `[fan]` only
+    pub alert_pin: Option<u8>,
+    /// Lock the controller configuration once applied, read from `[fan]` only
+    pub lock: bool,
+    /// PWM base frequency preset
+    pub pwm_frequency: PwmBase,
+    /// Divider applied to the PWM base frequency
+    pub pwm_divide: u8,
+    /// Logic of the PWM signal the fan expects
+    pub pwm_polarity: PwmPolarity,
+    /// Open-drain or push-pull PWM output
+    pub pwm_output: PwmOutput,
+    /// Spin-up settings
+    pub spin_up: SpinUpConfig,
+    /// Lowest drive of a running fan, percent, raises the low end of the curve
+    pub min_drive: Option<u8>,
+    /// Slowest speed, in RPM, the controller accepts before flagging a stall, 0 for the
+    /// slowest the tach range picked for min_drive measures
+    pub min_rpm: u32,
+    /// RPM baseline tracking
+    pub health: HealthConfig,
+    /// Fastest drive change, percent per second, 0 leaves it unlimited.
+    /// Rises to full speed are never limited.
+    pub ramp_rate: u8,
+}
+
+impl FanConfig {
+    /// The characteristics of the fan model, a typical PWM fan without one
+    fn preset(&self) -> Preset {
+        self.model.map(FanModel::preset).unwrap_or_default()
+    }
+
+    /// Tach pulses per revolution, 0 for a fan without tach
+    pub fn pulses_per_rev(&self) -> u8 {
+        self.pulses_per_rev
+            .unwrap_or_else(|| self.preset().pulses_per_rev)
+    }
+
+    /// The fan reports its speed
+    pub fn has_tach(&self) -> bool {
+        self.pulses_per_rev() > 0
+    }
+
+    /// Fan speed, in RPM, at full drive
+    pub fn max_rpm(&self) -> u32 {
+        self.max_rpm.unwrap_or_else(|| self.preset().max_rpm)
+    }
+
+    /// Lowest drive of a running fan, percent
+    fn min_drive_percent(&self) -> u8 {
+        self.min_drive.unwrap_or_else(|| self.preset().min_drive)
+    }
+
+    /// Lowest drive of a running fan as a fraction of full speed
+    pub fn min_drive(&self) -> f32 {
+        f32::from(self.min_drive_percent()) / 100.0
+    }
+
+    /// Slowest speed the tach must measure, the stall threshold or the speed at min_drive
+    pub fn slowest_rpm(&self) -> u32 {
+        match self.min_rpm {
+            0 => self.max_rpm() * u32::from(self.min_drive_percent()) / 100,
+            rpm => rpm,
+        }
+    }
+
+    /// Fan index on the controller, from 0
+    pub fn fan(&self) -> u8 {
+        self.channel - 1
+    }
+
+    /// Check every zone is found one way and the aggregation can combine them
+    fn validate_zones(&self) -> Result<(), String> {
+        if self.sensor.is_some() && !self.zones.is_empty() {
+            return Err("set either sensor or zones".into());
+        }
+        for zone in &self.zones {
+            let sources = [
+                zone.path.is_some(),
+                zone.kind.is_some(),
+                zone.hwmon.is_some(),
+                zone.i2c.is_some(),
+                zone.w1.is_some(),
+            ];
+            if sources.iter().filter(|&&set| set).count() != 1 {
+                return Err(format!(
+                    "zone {} needs one of a path, a type, a hwmon, an i2c or a w1 sensor",
+                    zone.label()
+                ));
+            }
+            if zone
+                .w1
+                .as_ref()
+                .is_some_and(|id| !id.starts_with(w1::DS18B20_FAMILY))
+            {
+                return Err(format!(
+                    "zone {} needs a DS18B20 probe ID, starting with {}",
+                    zone.label(),
+                    w1::DS18B20_FAMILY
+                ));
+            }
+            if zone.address.is_some_and(|address| address > 0x7F) {
+                return Err(format!("zone {} needs a 7 bit I2C address", zone.label()));
+            }
+            if zone.hwmon_label.is_some() && zone.hwmon.is_none() {
+                return Err(format!(
+                    "zone {} has a hwmon_label without hwmon",
+                    zone.label()
+                ));
+            }
+            if zone.hwmon_device.is_some() && zone.hwmon.is_none() {
+                return Err(format!(
+                    "zone {} has a hwmon_device without hwmon",
+                    zone.label()
+                ));
+            }
+            if zone.weight.is_nan() || zone.weight <= 0.0 {
+                return Err(format!("zone {} needs a weight above 0", zone.label()));
+            }
+        }
+        let labels: Vec<String> = self.zones.iter().map(ZoneConfig::label).collect();
+        if let Some(label) = labels
+            .iter()
+            .enumerate()
+            .find_map(|(index, label)| labels[..index].contains(label).then_some(label))
+        {
+            return Err(format!("zone {label} is configured twice"));
+        }
+        match &self.aggregation {
+            Aggregation::Zone(label) if !labels.contains(label) => {
+                Err(format!("aggregation zone {label} is not configured"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Check the settings of the channel are consistent
+    fn validate(&self) -> Result<(), String> {
+        if !(1..=MAX_CHANNELS).contains(&self.channel) {
+            return Err(format!("channel must be between 1 and {MAX_CHANNELS}"));
+        }
+        self.curve.validate()?;
+        self.validate_zones()?;
+        if let Some(nvme) = &self.nvme {
+            nvme.curve.validate().map_err(|err| format!("nvme {err}"))?;
+        }
+        if self.mode == ControlMode::Rpm && self.max_rpm() == 0 {
+            return Err("max_rpm must be above 0 in rpm mode".into());
+        }
+        if self.pulses_per_rev() > 4 {
+            return Err("pulses_per_rev must be between 0 and 4".into());
+        }
+        if !self.has_tach() && self.mode == ControlMode::Rpm {
+            return Err("rpm mode needs a fan with tach".into());
+        }
+        if !self.has_tach() && self.health.enabled {
+            return Err("health tracking needs a fan with tach".into());
+        }
+        if self.pwm_divide == 0 {
+            return Err("pwm_divide must be between 1 and 255".into());
+        }
+        if self.min_drive_percent() > 100 {
+            return Err("min_drive must be a percentage".into());
+        }
+        let min_drive_rpm = self.max_rpm() * u32::from(self.min_drive_percent()) / 100;
+        if self.has_tach() && self.min_rpm > 0 && self.min_rpm >= min_drive_rpm {
+            return Err(format!(
+                "min_rpm {} must be below the {min_drive_rpm} RPM expected at min_drive",
+                self.min_rpm
+            ));
+        }
+        if self.health.baseline_samples == 0 {
+            return Err("health baseline_samples must be above 0".into());
+        }
+        if self.spin_up.chip().bits().is_none() {
+            return Err(
+                "spin_up needs level 30 to 65 in steps of 5, time_ms 250, 500, 1000 or \
+                        2000 and drive_fail_count 0, 16, 32 or 64"
+                    .into(),
+            );
+        }
+        Ok(())
+    }
+}
+
+impl Default for FanConfig {
+    fn default() -> Self {
+        Self {
+            channel: 1,
+            sensor: None,
+            zones: Vec::new(),
+            aggregation: Aggregation::Max,
+            nvme: None,
+            curve: Curve::default(),
+            mode: ControlMode::Direct,
+            model: None,
+            pulses_per_rev: None,
+            max_rpm: None,
+            on_fault: FaultPolicy::Retry,
+            spin_up_retries: 3,
+            driver: Driver::Emc230x,
+            pwm_channel: 0,
+            gpio_pin: None,
+            pwm_hz: None,
+            hwmon_name: HWMON_NAME.to_string(),
+            sysfs_root: PathBuf::from(SYSFS_ROOT),
+            cooling_type: COOLING_TYPE.to_string(),
+            take_over: false,
+            unknown_chip: UnknownChip::Refuse,
+            alert_pin: None,
+            lock: false,
+            pwm_frequency: PwmBase::F26000,
+            pwm_divide: 1,
+            pwm_polarity: PwmPolarity::Normal,
+            pwm_output: PwmOutput::OpenDrain,
+            spin_up: SpinUpConfig::default(),
+            min_drive: None,
+            min_rpm: 0,
+            ramp_rate: 0,
+            health: HealthConfig::default(),
+        }
+    }
+}
+
+/// Service configuration
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Settings of the first fan, the only one on an EMC2301
+    pub fan: FanConfig,
+    /// Settings of the other fans of an EMC2302, EMC2303 or EMC2305
+    pub fans: Vec<FanConfig>,
+}
+
+impl Config {
+    /// Read the configuration file, a missing file gives the defaults
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => Self::parse(&text),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(format!("Unable to read {}: {err}", path.display()).into()),
+        }
+    }
+
+    /// Parse the configuration from TOML text
+    pub fn parse(text: &str) -> Result<Self, Box<dyn Error>> {
+        let config: Self = toml::from_str(text)?;
+        validate_fan_only(&toml::from_str(text)?)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Settings of every configured fan channel
+    pub fn channels(&self) -> impl Iterator<Item = &FanConfig> {
+        std::iter::once(&self.fan).chain(&self.fans)
+    }
+
+    /// Highest configured fan channel
+    pub fn max_channel(&self) -> u8 {
+        self.channels().map(|fan| fan.channel).max().unwrap_or(1)
+    }
+
+    /// Check the settings are consistent
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        let mut seen = Vec::new();
+        let mut outputs = Vec::new();
+        for fan in self.channels() {
+            fan.validate()
+                .and_then(|_| self.validate_output(fan))
+                .and_then(|_| self.validate_lock(fan))
+                .map_err(|err| format!("fan channel {}: {err}", fan.channel))?;
+            if seen.contains(&fan.channel) {
+                return Err(format!("fan channel {} is configured twice", fan.channel).into());
+            }
+            seen.push(fan.channel);
+            let output = match self.fan.driver {
+                Driver::Emc230x | Driver::Hwmon | Driver::Cooling => None,
+                Driver::Pwm => Some(fan.pwm_channel),
+                Driver::Gpio => fan.gpio_pin,
+            };
+            if let Some(output) = output {
+                if outputs.contains(&output) {
+                    return Err(format!(
+                        "fan channel {}: output {output} is already used",
+                        fan.channel
+                    )
+                    .into());
+                }
+                outputs.push(output);
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the lock leaves the settings a fan changes while running
+    fn validate_lock(&self, fan: &FanConfig) -> Result<(), String> {
+        if self.fan.lock && fan.mode == ControlMode::Rpm && fan.ramp_rate > 0 {
+            return Err(
+                "ramp_rate in rpm mode can't be locked, the chip ramp must be lifted for rises \
+                 to full speed"
+                    .into(),
+            );
+        }
+        Ok(())
+    }
+
+    /// Check a fan can be driven by the configured driver
+    fn validate_output(&self, fan: &FanConfig) -> Result<(), String> {
+        match self.fan.driver {
+            Driver::Emc230x => Ok(()),
+            _ if fan.mode == ControlMode::Rpm => Err("rpm mode needs the emc230x driver".into()),
+            _ if fan.health.enabled => Err("health tracking needs the emc230x driver".into()),
+            Driver::Pwm if fan.pwm_channel > 1 => Err("pwm_channel must be 0 or 1".into()),
+            Driver::Gpio if fan.gpio_pin.is_none() => Err("the gpio driver needs gpio_pin".into()),
+            _ if fan.pwm_hz.is_some_and(|hz| hz <= 0.0) => Err("pwm_hz must be positive".into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Check no `[[fans]]` table sets what is read from `[fan]` only, it would be ignored
+fn validate_fan_only(table: &toml::Table) -> Result<(), String> {
+    let fans = table.get("fans").and_then(toml::Value::as_array);
+    for fan in fans.into_iter().flatten() {
+        if let Some(key) = FAN_ONLY_KEYS.iter().find(|&&key| fan.get(key).is_some()) {
+            let channel = fan.get("channel").and_then(toml::Value::as_integer);
+            return Err(format!(
+                "fan channel {}: {key} applies to every fan, set it in [fan]",
+                channel.unwrap_or(1)
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fan_only_settings_are_read_from_fan() {
+        let config = Config::parse(
+            "[fan]\ndriver = \"pwm\"\nlock = true\n\n[[fans]]\nchannel = 2\npwm_channel = 1\n",
+        )
+        .unwrap();
+        assert_eq!(config.fan.driver, Driver::Pwm);
+        assert!(config.fan.lock);
+        assert_eq!(config.max_channel(), 2);
+    }
+
+    #[test]
+    fn fan_only_settings_are_rejected_in_fans() {
+        for setting in [
+            "driver = \"pwm\"",
+            "lock = true",
+            "alert_pin = 4",
+            "unknown_chip = \"direct\"",
+            "sysfs_root = \"/tmp\"",
+            "hwmon_name = \"emc2305\"",
+            "take_over = true",
+        ] {
+            let text = format!("[[fans]]\nchannel = 2\n{setting}\n");
+            let err = Config::parse(&text).unwrap_err().to_string();
+            let key = setting.split(' ').next().unwrap();
+            assert_eq!(
+                err,
+                format!("fan channel 2: {key} applies to every fan, set it in [fan]")
+            );
+        }
+    }
+
+    #[test]
+    fn locked_rpm_mode_has_no_ramp_rate() {
+        let ramp = "[fan]\nlock = true\nmode = \"rpm\"\nramp_rate = 10\n";
+        let err = Config::parse(ramp).unwrap_err().to_string();
+        assert!(
+            err.starts_with("fan channel 1: ramp_rate in rpm mode"),
+            "{err}"
+        );
+        // Ramped in software, direct mode needs nothing of the chip
+        assert!(Config::parse("[fan]\nlock = true\nramp_rate = 10\n").is_ok());
+        assert!(Config::parse("[fan]\nlock = true\nmode = \"rpm\"\n").is_ok());
+    }
+
+    #[test]
+    fn w1_zones_need_a_ds18b20_id() {
+        let probe = |id: &str| format!("[fan]\n[[fan.zones]]\nw1 = \"{id}\"\n");
+        assert!(Config::parse(&probe("28-0316a2794aff")).is_ok());
+        let err = Config::parse(&probe("10-000802b4ba0b"))
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            err,
+            "fan channel 1: zone 10-000802b4ba0b needs a DS18B20 probe ID, starting with 28-"
+        );
+    }
+}
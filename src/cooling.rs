@@ -0,0 +1,250 @@
+//! Fans exposed as thermal cooling devices, driven through their discrete states
+
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{config::Config, driver::FanDriver};
+
+/// Directory of the thermal zones and cooling devices under the sysfs root
+const THERMAL_CLASS: &str = "class/thermal";
+/// Thermal zone policy leaving the cooling devices to user space
+const USER_SPACE: &str = "user_space";
+
+/// The cooling device whose `type` attribute is `kind`, under the sysfs `root`
+pub fn find(root: &Path, kind: &str) -> Result<PathBuf, Box<dyn Error>> {
+    let class = root.join(THERMAL_CLASS);
+    let mut devices = entries(&class, "cooling_device")?;
+    devices.sort();
+    devices
+        .into_iter()
+        .find(|device| {
+            fs::read_to_string(device.join("type")).is_ok_and(|found| found.trim() == kind)
+        })
+        .ok_or_else(|| format!("No cooling device of type {kind} in {}", class.display()).into())
+}
+
+/// Entries of `dir` whose name starts with `prefix`
+fn entries(dir: &Path, prefix: &str) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let entries =
+        fs::read_dir(dir).map_err(|err| format!("Unable to list {}: {err}", dir.display()))?;
+    Ok(entries
+        .flatten()
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with(prefix))
+        .map(|entry| entry.path())
+        .collect())
+}
+
+/// The thermal zones with `device` bound through one of their `cdevN` links
+fn bound_zones(root: &Path, device: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let device = fs::canonicalize(device)?;
+    let mut zones = Vec::new();
+    for zone in entries(&root.join(THERMAL_CLASS), "thermal_zone")? {
+        // cdevN links to the device, next to its cdevN_trip_point and cdevN_weight files
+        let bound = entries(&zone, "cdev")?.iter().any(|cdev| {
+            cdev.is_symlink() && fs::canonicalize(cdev).is_ok_and(|target| target == device)
+        });
+        if bound {
+            zones.push(zone);
+        }
+    }
+    zones.sort();
+    Ok(zones)
+}
+
+/// Read a sysfs attribute
+fn read(path: &Path) -> Result<String, Box<dyn Error>> {
+    let value = fs::read_to_string(path)
+        .map_err(|err| format!("Unable to read {}: {err}", path.display()))?;
+    Ok(value.trim().to_string())
+}
+
+/// Write a sysfs attribute
+fn write(path: &Path, value: &str) -> Result<(), Box<dyn Error>> {
+    fs::write(path, value).map_err(|err| format!("Unable to write {}: {err}", path.display()))?;
+    Ok(())
+}
+
+/// One cooling device
+struct Device {
+    path: PathBuf,
+    max_state: u32,
+}
+
+impl Device {
+    /// The lowest state giving at least `duty`, so a running fan never maps to off
+    fn state(&self, duty: u8) -> u32 {
+        (u32::from(duty) * self.max_state).div_ceil(u32::from(u8::MAX))
+    }
+}
+
+/// Fans of thermal cooling devices, found by type for each fan channel
+pub struct CoolingFans {
+    devices: BTreeMap<u8, Device>,
+    /// Thermal zone policies replaced at startup, restored on exit
+    policies: Vec<(PathBuf, String)>,
+}
+
+impl CoolingFans {
+    /// Find the device of every configured fan, taking them from the kernel when asked
+    pub fn open(config: &Config) -> Result<Self, Box<dyn Error>> {
+        let root = &config.fan.sysfs_root;
+        let mut fans = Self {
+            devices: BTreeMap::new(),
+            policies: Vec::new(),
+        };
+        for fan in config.channels() {
+            let path = find(root, &fan.cooling_type)?;
+            let max_state = read(&path.join("max_state"))?
+                .parse()
+                .map_err(|err| format!("Bad max_state in {}: {err}", path.display()))?;
+            println!(
+                "Fan channel {}: cooling device {} with {max_state} states",
+                fan.channel,
+                path.display()
+            );
+            if config.fan.take_over {
+                fans.take_over(root, &path)?;
+            }
+            fans.devices.insert(fan.fan(), Device { path, max_state });
+        }
+        Ok(fans)
+    }
+
+    /// Leave the states of `device` to user space in every zone it is bound to
+    fn take_over(&mut self, root: &Path, device: &Path) -> Result<(), Box<dyn Error>> {
+        for zone in bound_zones(root, device)? {
+            let policy = zone.join("policy");
+            let governor = read(&policy)?;
+            if governor == USER_SPACE || self.policies.iter().any(|(path, _)| *path == policy) {
+                continue;
+            }
+            write(&policy, USER_SPACE)?;
+            println!("Took {} over from the {governor} governor", zone.display());
+            self.policies.push((policy, governor));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for CoolingFans {
+    fn drop(&mut self) {
+        // Give the zones back to the governors they had
+        for (policy, governor) in &self.policies {
+            if let Err(err) = write(policy, governor) {
+                eprintln!("{err}");
+            }
+        }
+    }
+}
+
+impl fmt::Display for CoolingFans {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the thermal cooling devices")
+    }
+}
+
+impl FanDriver for CoolingFans {
+    fn set_duty(&mut self, fan: u8, duty: u8) -> Result<(), Box<dyn Error>> {
+        let device = self
+            .devices
+            .get(&fan)
+            .ok_or_else(|| format!("fan {} has no cooling device", fan + 1))?;
+        let state = device.state(duty);
+        write(&device.path.join("cur_state"), &state.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{Driver, FanConfig},
+        testing::TempTree,
+    };
+
+    /// A sysfs tree with a CPU cooling device, a pwm-fan with 3 states bound to the step_wise
+    /// thermal_zone0, and thermal_zone1 left to its governor
+    fn sysfs(name: &str) -> TempTree {
+        let tree = TempTree::new(name);
+        tree.file("class/thermal/cooling_device0/type", "Processor\n");
+        tree.file("class/thermal/cooling_device1/type", "pwm-fan\n");
+        tree.file("class/thermal/cooling_device1/max_state", "3\n");
+        tree.file("class/thermal/cooling_device1/cur_state", "0\n");
+        tree.file("class/thermal/thermal_zone0/type", "pwm-fan\n");
+        tree.file("class/thermal/thermal_zone0/policy", "step_wise\n");
+        tree.link("../cooling_device1", "class/thermal/thermal_zone0/cdev0");
+        tree.file("class/thermal/thermal_zone0/cdev0_trip_point", "0\n");
+        tree.file("class/thermal/thermal_zone1/policy", "step_wise\n");
+        tree.link("../cooling_device0", "class/thermal/thermal_zone1/cdev0");
+        tree
+    }
+
+    #[test]
+    fn running_fans_never_map_to_off() {
+        let device = Device {
+            path: PathBuf::new(),
+            max_state: 3,
+        };
+        let states: Vec<u32> = [0, 1, 85, 86, 170, 171, 255]
+            .into_iter()
+            .map(|duty| device.state(duty))
+            .collect();
+        assert_eq!(states, [0, 1, 1, 2, 2, 3, 3]);
+        let device = Device {
+            path: PathBuf::new(),
+            max_state: 10,
+        };
+        assert_eq!(device.state(255), 10);
+        assert_eq!(device.state(1), 1);
+    }
+
+    #[test]
+    fn find_matches_the_device_type() {
+        let tree = sysfs("cooling_find");
+        let class = tree.path().join(THERMAL_CLASS);
+        assert_eq!(
+            find(tree.path(), "pwm-fan").unwrap(),
+            class.join("cooling_device1")
+        );
+        let err = find(tree.path(), "gpio-fan").unwrap_err().to_string();
+        assert_eq!(
+            err,
+            format!("No cooling device of type gpio-fan in {}", class.display())
+        );
+    }
+
+    #[test]
+    fn bound_zones_are_taken_over_and_handed_back() {
+        let tree = sysfs("cooling_take_over");
+        let class = tree.path().join(THERMAL_CLASS);
+        let device = class.join("cooling_device1");
+        assert_eq!(
+            bound_zones(tree.path(), &device).unwrap(),
+            [class.join("thermal_zone0")]
+        );
+        let config = Config {
+            fan: FanConfig {
+                driver: Driver::Cooling,
+                sysfs_root: tree.path().to_path_buf(),
+                cooling_type: "pwm-fan".into(),
+                take_over: true,
+                ..FanConfig::default()
+            },
+            fans: Vec::new(),
+        };
+        let read = |path: &str| fs::read_to_string(class.join(path)).unwrap();
+        let mut fans = CoolingFans::open(&config).unwrap();
+        assert_eq!(read("thermal_zone0/policy"), USER_SPACE);
+        assert_eq!(read("thermal_zone1/policy"), "step_wise\n");
+        fans.set_duty(0, 255).unwrap();
+        assert_eq!(read("cooling_device1/cur_state"), "3");
+        fans.set_duty(0, 1).unwrap();
+        assert_eq!(read("cooling_device1/cur_state"), "1");
+        drop(fans);
+        assert_eq!(read("thermal_zone0/policy"), "step_wise");
+    }
+}
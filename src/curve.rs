@@ -0,0 +1,100 @@
+//! Fan speed vs temperature curve
+
+use std::f32::consts::PI;
+
+use serde::Deserialize;
+
+/// Temperature below which to stop the fan
+const OFF_TEMP: f32 = 40.0;
+/// Temperature above which to start the fan
+const MIN_TEMP: f32 = 45.0;
+/// Temperature above which to reach full fan speed
+const MAX_TEMP: f32 = 75.0;
+
+/// The speed percentage that the fan is off at
+const FAN_OFF: f32 = 0.0;
+/// The speed percentage for lowest fan speed
+const FAN_LOW: f32 = 0.1;
+/// The speed percentage for full fan speed
+const FAN_MAX: f32 = 1.0;
+/// The max speed setting
+pub const MAX_SPEED: f32 = 255.0;
+
+/// The temperatures and lowest speed shaping a fan curve
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Curve {
+    /// Temperature below which to stop the fan
+    pub off_temp: f32,
+    /// Temperature above which to start the fan
+    pub min_temp: f32,
+    /// Temperature above which to reach full fan speed
+    pub max_temp: f32,
+    /// The speed percentage for lowest fan speed
+    pub low_speed: u8,
+}
+
+impl Default for Curve {
+    fn default() -> Self {
+        Self {
+            off_temp: OFF_TEMP,
+            min_temp: MIN_TEMP,
+            max_temp: MAX_TEMP,
+            low_speed: (FAN_LOW * 100.0) as u8,
+        }
+    }
+}
+
+impl Curve {
+    /// The speed fraction for lowest fan speed
+    #[inline]
+    fn fan_low(&self) -> f32 {
+        f32::from(self.low_speed) / 100.0
+    }
+
+    /// The slope of the fan speed vs temperature
+    #[inline]
+    fn fan_gain(&self) -> f32 {
+        (FAN_MAX - self.fan_low()) / (self.max_temp - self.min_temp)
+    }
+
+    /// The fan percentage curve
+    #[inline]
+    fn fan_curve(&self, temp: f32) -> f32 {
+        (0.5 * (1.0 - ((PI * temp) / 50.0).sin())
+            + (self.fan_low() + ((temp - self.min_temp).min(self.max_temp) * self.fan_gain())))
+            / 2.0
+    }
+
+    /// The fan percentage vs temperature
+    #[inline]
+    pub fn percentage(&self, temp: f32) -> f32 {
+        match temp {
+            t if t < self.off_temp => FAN_OFF,
+            t if t < self.min_temp => self.fan_low(),
+            t if t < self.max_temp => self.fan_curve(t),
+            _ => FAN_MAX,
+        }
+    }
+
+    /// The fan speed vs temperature, never below `min_drive` while running
+    #[inline]
+    pub fn speed(&self, temp: f32, min_drive: f32) -> u8 {
+        let fan_percentage = match self.percentage(temp) {
+            p if p > FAN_OFF => p.max(min_drive),
+            p => p,
+        };
+        (MAX_SPEED * fan_percentage).floor() as u8
+    }
+
+    /// Check the temperatures are in order and the speed is a percentage
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.off_temp <= self.min_temp && self.min_temp < self.max_temp) {
+            return Err("curve needs off_temp <= min_temp < max_temp".into());
+        }
+        if self.low_speed > 100 {
+            return Err("curve.low_speed must be a percentage".into());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,21 @@
+//! The outputs the control loop drives fans through
+
+use std::{error::Error, fmt};
+
+use crate::emc2301::Registers;
+
+/// Output driving the fans, each known by its index from 0
+pub trait FanDriver: fmt::Display {
+    /// Drive a fan at a PWM duty, 0 to 255
+    fn set_duty(&mut self, fan: u8, duty: u8) -> Result<(), Box<dyn Error>>;
+
+    /// Measured speed of a fan in RPM, when the driver has a tachometer
+    fn rpm(&mut self, _fan: u8) -> Option<u32> {
+        None
+    }
+
+    /// The registers of an identified EMC230x, for its tach, faults and watchdog
+    fn registers(&mut self) -> Option<&mut dyn Registers> {
+        None
+    }
+}
@@ -0,0 +1,575 @@
+//! Decoded dump of every documented EMC230x register
+
+use std::{collections::BTreeMap, error::Error, fmt, path::Path};
+
+use serde_json::{json, Value};
+
+use crate::emc2301::{
+    self, ChipId, PwmBase, Registers, TachConfig, CONFIGURATION, DRIVE_FAIL_BAND_HIGH,
+    DRIVE_FAIL_BAND_LOW, DRIVE_FAIL_STATUS, FAN_CONFIG1, FAN_CONFIG2, FAN_INTERRUPT_ENABLE,
+    FAN_MAX_STEP, FAN_MIN_DRIVE, FAN_SETTING, FAN_SPIN_STATUS, FAN_SPIN_UP, FAN_STALL_STATUS,
+    FAN_STATUS, GAIN, MANUFACTURER_ID, PRODUCT_ID, PWM_BASE, PWM_BASE45, PWM_DIVIDE, PWM_OUTPUT,
+    PWM_POLARITY, REVISION, SOFTWARE_LOCK, TACH_READING_HIGH, TACH_READING_LOW, TACH_TARGET_HIGH,
+    TACH_TARGET_LOW, VALID_TACH_COUNT,
+};
+
+/// Registers shared by all fans, before the fan blocks
+const CHIP_REGISTERS: &[(u8, &str)] = &[
+    (CONFIGURATION, "Configuration"),
+    (FAN_STATUS, "Fan Status"),
+    (FAN_STALL_STATUS, "Fan Stall Status"),
+    (FAN_SPIN_STATUS, "Fan Spin Status"),
+    (DRIVE_FAIL_STATUS, "Drive Fail Status"),
+    (FAN_INTERRUPT_ENABLE, "Fan Interrupt Enable"),
+    (PWM_POLARITY, "PWM Polarity Config"),
+    (PWM_OUTPUT, "PWM Output Config"),
+    (PWM_BASE45, "PWM Base Frequency 4-5"),
+    (PWM_BASE, "PWM Base Frequency"),
+];
+
+/// Registers repeated for each fan, at the addresses of the first fan
+const FAN_REGISTERS: &[(u8, &str)] = &[
+    (FAN_SETTING, "Fan Setting"),
+    (PWM_DIVIDE, "PWM Divide"),
+    (FAN_CONFIG1, "Fan Configuration 1"),
+    (FAN_CONFIG2, "Fan Configuration 2"),
+    (GAIN, "Gain"),
+    (FAN_SPIN_UP, "Fan Spin Up Configuration"),
+    (FAN_MAX_STEP, "Fan Max Step"),
+    (FAN_MIN_DRIVE, "Fan Minimum Drive"),
+    (VALID_TACH_COUNT, "Valid TACH Count"),
+    (DRIVE_FAIL_BAND_LOW, "Fan Drive Fail Band Low Byte"),
+    (DRIVE_FAIL_BAND_HIGH, "Fan Drive Fail Band High Byte"),
+    (TACH_TARGET_LOW, "TACH Target Low Byte"),
+    (TACH_TARGET_HIGH, "TACH Target High Byte"),
+    (TACH_READING_HIGH, "TACH Reading High Byte"),
+    (TACH_READING_LOW, "TACH Reading Low Byte"),
+];
+
+/// Registers after the fan blocks
+const ID_REGISTERS: &[(u8, &str)] = &[
+    (SOFTWARE_LOCK, "Software Lock"),
+    (PRODUCT_ID, "Product ID"),
+    (MANUFACTURER_ID, "Manufacturer ID"),
+    (REVISION, "Revision"),
+];
+
+/// Per fan bit names of the status and interrupt registers
+const FAN_BITS: [&str; 5] = ["FAN1", "FAN2", "FAN3", "FAN4", "FAN5"];
+/// Per fan bit names of PWM Polarity Config
+const POLARITY_BITS: [&str; 5] = [
+    "POLARITY1",
+    "POLARITY2",
+    "POLARITY3",
+    "POLARITY4",
+    "POLARITY5",
+];
+/// Per fan bit names of PWM Output Config
+const PMOT_BITS: [&str; 5] = ["PMOT1", "PMOT2", "PMOT3", "PMOT4", "PMOT5"];
+/// Per fan field names of the PWM Base Frequency registers
+const BASE_FIELDS: [&str; 5] = ["BASE1", "BASE2", "BASE3", "BASE4", "BASE5"];
+
+/// The documented registers of a controller driving `fans` fans, in address order
+fn registers(fans: u8) -> Vec<(u8, String)> {
+    let mut registers: Vec<(u8, String)> = CHIP_REGISTERS
+        .iter()
+        .filter(|&&(address, _)| address != PWM_BASE45 || fans > 3)
+        .map(|&(address, name)| (address, name.to_string()))
+        .collect();
+    for fan in 0..fans {
+        for &(address, name) in FAN_REGISTERS {
+            let name = if fans > 1 {
+                format!("Fan {} {name}", fan + 1)
+            } else {
+                name.to_string()
+            };
+            registers.push((emc2301::fan_register(fan, address), name));
+        }
+    }
+    registers.extend(
+        ID_REGISTERS
+            .iter()
+            .map(|&(address, name)| (address, name.to_string())),
+    );
+    registers
+}
+
+/// Name of a register of a controller driving `fans` fans
+pub fn register_name(address: u8, fans: u8) -> String {
+    registers(fans)
+        .into_iter()
+        .find(|&(reg, _)| reg == address)
+        .map_or_else(|| format!("register {address:#04x}"), |(_, name)| name)
+}
+
+/// Output format of the dump
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Table,
+    Json,
+}
+
+impl Format {
+    /// Parse a `--format` argument
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "table" => Some(Format::Table),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+}
+
+/// Register values, read from the controller or a recorded dump
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Snapshot(BTreeMap<u8, u8>);
+
+impl Snapshot {
+    /// Read every documented register. Reading clears the status registers.
+    pub fn read(regs: &mut dyn Registers) -> rppal::i2c::Result<Self> {
+        let mut snapshot = Self::default();
+        for &(address, _) in ID_REGISTERS {
+            snapshot.0.insert(address, regs.read(address)?);
+        }
+        for (address, _) in registers(snapshot.fans()) {
+            snapshot.0.insert(address, regs.read(address)?);
+        }
+        Ok(snapshot)
+    }
+
+    /// Load a snapshot recorded with `dump-registers --format json`
+    pub fn load(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|err| format!("Unable to read {}: {err}", path.display()))?;
+        Self::parse(&text)
+    }
+
+    /// Parse a snapshot from the JSON dump output
+    pub fn parse(text: &str) -> Result<Self, Box<dyn Error>> {
+        let dump: Value = serde_json::from_str(text)?;
+        let registers = dump["registers"]
+            .as_array()
+            .ok_or("Snapshot has no registers list")?;
+        let mut values = BTreeMap::new();
+        for register in registers {
+            let address = register["address"]
+                .as_str()
+                .and_then(|a| u8::from_str_radix(a.trim_start_matches("0x"), 16).ok())
+                .ok_or("Snapshot register without a valid address")?;
+            let value = register["value"]
+                .as_u64()
+                .and_then(|v| u8::try_from(v).ok())
+                .ok_or("Snapshot register without a valid value")?;
+            values.insert(address, value);
+        }
+        Ok(Self(values))
+    }
+
+    /// Value of a register, 0 when it was not recorded
+    pub fn get(&self, address: u8) -> u8 {
+        self.0.get(&address).copied().unwrap_or_default()
+    }
+
+    /// The identification registers
+    fn chip_id(&self) -> ChipId {
+        ChipId {
+            product: self.get(PRODUCT_ID),
+            manufacturer: self.get(MANUFACTURER_ID),
+            revision: self.get(REVISION),
+        }
+    }
+
+    /// Number of fans of the recorded controller, 1 when unknown
+    pub fn fans(&self) -> u8 {
+        self.chip_id().product().map_or(1, |product| product.fans())
+    }
+}
+
+/// A decoded field of a register
+#[derive(Debug, Clone, PartialEq)]
+pub enum Field {
+    Flag(bool),
+    Number(u32),
+    Text(String),
+}
+
+impl Field {
+    fn json(&self) -> Value {
+        match self {
+            Field::Flag(flag) => json!(flag),
+            Field::Number(number) => json!(number),
+            Field::Text(text) => json!(text),
+        }
+    }
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Field::Flag(flag) => write!(f, "{}", u8::from(*flag)),
+            Field::Number(number) => write!(f, "{number}"),
+            Field::Text(text) => write!(f, "{text}"),
+        }
+    }
+}
+
+/// A register with its decoded fields
+#[derive(Debug, Clone, PartialEq)]
+pub struct RegisterDump {
+    pub address: u8,
+    pub name: String,
+    pub value: u8,
+    pub fields: Vec<(&'static str, Field)>,
+}
+
+/// A single bit as a flag field
+fn flag(name: &'static str, value: u8, bit: u8) -> (&'static str, Field) {
+    (name, Field::Flag(value & (1 << bit) != 0))
+}
+
+/// A speed in RPM as a text field
+fn rpm(name: &'static str, rpm: u32) -> (&'static str, Field) {
+    (name, Field::Text(format!("{rpm} RPM")))
+}
+
+/// A drive value as a percentage text field
+fn percent(name: &'static str, value: u8) -> (&'static str, Field) {
+    let percent = f32::from(value) * 100.0 / 255.0;
+    (name, Field::Text(format!("{percent:.1}%")))
+}
+
+/// One flag per fan of a per fan bit register
+fn fan_flags(names: &[&'static str; 5], value: u8, fans: u8) -> Vec<(&'static str, Field)> {
+    (0..fans)
+        .map(|fan| flag(names[usize::from(fan)], value, fan))
+        .collect()
+}
+
+/// The PWM base frequency fields of the fans found in a base register
+fn pwm_bases(address: u8, value: u8, fans: u8) -> Vec<(&'static str, Field)> {
+    (0..fans)
+        .filter_map(|fan| {
+            let (reg, shift) = emc2301::pwm_base_field(fan);
+            (reg == address).then(|| {
+                let base = PwmBase::from_bits(value >> shift);
+                (
+                    BASE_FIELDS[usize::from(fan)],
+                    Field::Text(format!("{:.0} Hz", base.hz())),
+                )
+            })
+        })
+        .collect()
+}
+
+/// The fields of one register
+fn decode_register(address: u8, snapshot: &Snapshot) -> Vec<(&'static str, Field)> {
+    let fans = snapshot.fans();
+    let block = address.wrapping_sub(FAN_SETTING);
+    if block < 0x10 * fans {
+        let fan = block / 0x10;
+        return decode_fan_register(FAN_SETTING + block % 0x10, fan, snapshot);
+    }
+    let value = snapshot.get(address);
+    match address {
+        CONFIGURATION => vec![
+            flag("MASK", value, 7),
+            flag("DIS_TO", value, 6),
+            flag("WD_EN", value, 5),
+            flag("DR_EXT_CLK", value, 1),
+            flag("USE_EXT_CLK", value, 0),
+        ],
+        FAN_STATUS => vec![
+            flag("WATCH", value, 7),
+            flag("DRIVE_FAIL", value, 2),
+            flag("FAN_SPIN", value, 1),
+            flag("FAN_STALL", value, 0),
+        ],
+        FAN_STALL_STATUS | FAN_SPIN_STATUS | DRIVE_FAIL_STATUS | FAN_INTERRUPT_ENABLE => {
+            fan_flags(&FAN_BITS, value, fans)
+        }
+        PWM_POLARITY => fan_flags(&POLARITY_BITS, value, fans),
+        PWM_OUTPUT => (0..fans)
+            .map(|fan| {
+                let output = if value & (1 << fan) != 0 {
+                    "push-pull"
+                } else {
+                    "open-drain"
+                };
+                (PMOT_BITS[usize::from(fan)], Field::Text(output.into()))
+            })
+            .collect(),
+        PWM_BASE | PWM_BASE45 => pwm_bases(address, value, fans),
+        SOFTWARE_LOCK => vec![flag("LOCK", value, 0)],
+        PRODUCT_ID => {
+            let name = match snapshot.chip_id().product() {
+                Some(product) => product.to_string(),
+                None => "unknown".into(),
+            };
+            vec![("PRODUCT", Field::Text(name))]
+        }
+        MANUFACTURER_ID => {
+            let name = if value == emc2301::MICROCHIP_ID {
+                "Microchip"
+            } else {
+                "unknown"
+            };
+            vec![("MANUFACTURER", Field::Text(name.into()))]
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// The fields of a fan block register, given at the address of the first fan
+fn decode_fan_register(address: u8, fan: u8, snapshot: &Snapshot) -> Vec<(&'static str, Field)> {
+    let get = |reg| snapshot.get(emc2301::fan_register(fan, reg));
+    let value = get(address);
+    let tach = TachConfig::from_fan_config1(get(FAN_CONFIG1));
+    match address {
+        FAN_SETTING | FAN_MIN_DRIVE => vec![percent("DRIVE", value)],
+        PWM_DIVIDE => vec![("DIVIDE", Field::Number(value.into()))],
+        FAN_CONFIG1 => vec![
+            flag("EN_ALGO", value, 7),
+            (
+                "RANGE",
+                Field::Text(format!("{} RPM min", 500 * u32::from(tach.multiplier))),
+            ),
+            ("EDGES", Field::Number(tach.edges.into())),
+            ("POLES", Field::Number(tach.poles.into())),
+            (
+                "UPDATE",
+                Field::Text(format!("{} ms", emc2301::update_time_ms(value))),
+            ),
+        ],
+        FAN_CONFIG2 => vec![
+            flag("EN_RRC", value, 6),
+            flag("GLITCH_EN", value, 5),
+            ("DER_OPT", Field::Number(((value >> 3) & 0x03).into())),
+            (
+                "ERR_RNG",
+                Field::Text(format!(
+                    "{} RPM",
+                    [0, 50, 100, 200][usize::from((value >> 1) & 0x03)]
+                )),
+            ),
+        ],
+        GAIN => vec![
+            ("GAIND", Field::Number(1 << ((value >> 4) & 0x03))),
+            ("GAINI", Field::Number(1 << ((value >> 2) & 0x03))),
+            ("GAINP", Field::Number(1 << (value & 0x03))),
+        ],
+        FAN_SPIN_UP => vec![
+            (
+                "DRIVE_FAIL_CNT",
+                Field::Number([0, 16, 32, 64][usize::from(value >> 6)]),
+            ),
+            flag("NOKICK", value, 5),
+            (
+                "SPIN_LVL",
+                Field::Text(format!("{}%", 30 + 5 * ((value >> 2) & 0x07))),
+            ),
+            (
+                "SPINUP_TIME",
+                Field::Text(format!(
+                    "{} ms",
+                    [250, 500, 1000, 2000][usize::from(value & 0x03)]
+                )),
+            ),
+        ],
+        FAN_MAX_STEP => vec![("MAX_STEP", Field::Number((value & 0x3F).into()))],
+        VALID_TACH_COUNT => vec![rpm("MIN_RPM", tach.rpm(emc2301::tach_from_bytes(value, 0)))],
+        DRIVE_FAIL_BAND_HIGH => {
+            let count = emc2301::tach_from_bytes(value, get(DRIVE_FAIL_BAND_LOW));
+            vec![("COUNT", Field::Number(count.into()))]
+        }
+        TACH_TARGET_HIGH => {
+            let count = emc2301::tach_from_bytes(value, get(TACH_TARGET_LOW));
+            vec![
+                ("COUNT", Field::Number(count.into())),
+                rpm("TARGET", tach.rpm(count)),
+            ]
+        }
+        TACH_READING_HIGH => {
+            let count = emc2301::tach_from_bytes(value, get(TACH_READING_LOW));
+            vec![
+                ("COUNT", Field::Number(count.into())),
+                rpm("SPEED", tach.rpm(count)),
+            ]
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Decode every documented register of a snapshot
+pub fn decode(snapshot: &Snapshot) -> Vec<RegisterDump> {
+    registers(snapshot.fans())
+        .into_iter()
+        .map(|(address, name)| RegisterDump {
+            address,
+            name,
+            value: snapshot.get(address),
+            fields: decode_register(address, snapshot),
+        })
+        .collect()
+}
+
+/// Render the decoded registers as a text table
+pub fn table(dump: &[RegisterDump]) -> String {
+    let width = dump
+        .iter()
+        .map(|register| register.name.len())
+        .max()
+        .unwrap_or(0);
+    let mut out = format!(
+        "{:<4}  {:<width$}  {:<5}  Decoded\n",
+        "Reg", "Name", "Value"
+    );
+    for register in dump {
+        let fields: Vec<String> = register
+            .fields
+            .iter()
+            .map(|(name, field)| format!("{name}={field}"))
+            .collect();
+        let line = format!(
+            "{:#04x}  {:<width$}  {:#04x}   {}",
+            register.address,
+            register.name,
+            register.value,
+            fields.join(" ")
+        );
+        out += line.trim_end();
+        out += "\n";
+    }
+    out
+}
+
+/// Render the decoded registers as JSON, loadable again as a snapshot
+pub fn json(dump: &[RegisterDump]) -> String {
+    let registers: Vec<Value> = dump
+        .iter()
+        .map(|register| {
+            let fields: serde_json::Map<String, Value> = register
+                .fields
+                .iter()
+                .map(|(name, field)| (name.to_string(), field.json()))
+                .collect();
+            json!({
+                "address": format!("{:#04x}", register.address),
+                "name": register.name,
+                "value": register.value,
+                "fields": fields,
+            })
+        })
+        .collect();
+    serde_json::to_string_pretty(&json!({ "registers": registers })).unwrap_or_default()
+}
+
+/// Render the decoded registers in `format`
+pub fn render(dump: &[RegisterDump], format: Format) -> String {
+    match format {
+        Format::Table => table(dump),
+        Format::Json => json(dump),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A running EMC2302 recorded with `dump-registers --format json`: fan 1 in rpm mode,
+    /// fan 2 in direct mode and stalled, the configuration locked
+    const RECORDED: &str = include_str!("../tests/fixtures/emc2302_snapshot.json");
+
+    fn recorded() -> Snapshot {
+        Snapshot::parse(RECORDED).unwrap()
+    }
+
+    /// The decoded register at `address`
+    fn register(dump: &[RegisterDump], address: u8) -> &RegisterDump {
+        dump.iter()
+            .find(|register| register.address == address)
+            .unwrap()
+    }
+
+    #[test]
+    fn parse_reads_the_recorded_values() {
+        let snapshot = recorded();
+        assert_eq!(snapshot.fans(), 2);
+        assert_eq!(snapshot.get(PRODUCT_ID), 0x36);
+        assert_eq!(snapshot.get(emc2301::fan_register(1, FAN_SETTING)), 0x80);
+        // Registers not recorded read as 0
+        assert_eq!(snapshot.get(0x50), 0);
+    }
+
+    #[test]
+    fn parse_rejects_malformed_snapshots() {
+        assert!(Snapshot::parse("{}").is_err());
+        assert!(Snapshot::parse(r#"{"registers": [{"address": "0x1g", "value": 1}]}"#).is_err());
+        assert!(Snapshot::parse(r#"{"registers": [{"address": "0x20", "value": 256}]}"#).is_err());
+    }
+
+    #[test]
+    fn decode_lists_both_fan_blocks() {
+        let dump = decode(&recorded());
+        assert_eq!(
+            dump.len(),
+            CHIP_REGISTERS.len() - 1 + 2 * FAN_REGISTERS.len() + 4
+        );
+        assert_eq!(register(&dump, 0x4e).name, "Fan 2 TACH Reading High Byte");
+        // PWM Base Frequency 4-5 only exists on the EMC2305
+        assert!(dump.iter().all(|register| register.address != PWM_BASE45));
+    }
+
+    #[test]
+    fn table_decodes_the_recorded_registers() {
+        let table = table(&decode(&recorded()));
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(
+            lines[0],
+            "Reg   Name                                 Value  Decoded"
+        );
+        for line in [
+            "0x20  Configuration                        0x60   MASK=0 DIS_TO=1 WD_EN=1 DR_EXT_CLK=0 USE_EXT_CLK=0",
+            "0x25  Fan Stall Status                     0x02   FAN1=0 FAN2=1",
+            "0x2b  PWM Output Config                    0x01   PMOT1=push-pull PMOT2=open-drain",
+            "0x2d  PWM Base Frequency                   0x08   BASE1=26000 Hz BASE2=4882 Hz",
+            "0x32  Fan 1 Fan Configuration 1            0x8b   EN_ALGO=1 RANGE=500 RPM min EDGES=5 POLES=2 UPDATE=400 ms",
+            "0x39  Fan 1 Valid TACH Count               0xfe   MIN_RPM=483 RPM",
+            "0x3d  Fan 1 TACH Target High Byte          0x31   COUNT=1572 TARGET=2501 RPM",
+            "0x3e  Fan 1 TACH Reading High Byte         0x31   COUNT=1580 SPEED=2488 RPM",
+            "0x4e  Fan 2 TACH Reading High Byte         0xff   COUNT=8191 SPEED=0 RPM",
+            "0xef  Software Lock                        0x01   LOCK=1",
+            "0xfd  Product ID                           0x36   PRODUCT=EMC2302",
+            "0xff  Revision                             0x80",
+        ] {
+            assert!(lines.contains(&line), "missing {line}");
+        }
+    }
+
+    #[test]
+    fn json_decodes_the_recorded_registers() {
+        let dump: Value = serde_json::from_str(&json(&decode(&recorded()))).unwrap();
+        let registers = dump["registers"].as_array().unwrap();
+        let find = |address: &str| {
+            registers
+                .iter()
+                .find(|register| register["address"] == address)
+                .unwrap()
+        };
+        let reading = find("0x3e");
+        assert_eq!(reading["name"], "Fan 1 TACH Reading High Byte");
+        assert_eq!(reading["value"], 0x31);
+        assert_eq!(reading["fields"]["COUNT"], 1580);
+        assert_eq!(reading["fields"]["SPEED"], "2488 RPM");
+        assert_eq!(find("0x25")["fields"]["FAN2"], true);
+        assert_eq!(find("0x41")["fields"]["DIVIDE"], 2);
+        assert_eq!(find("0x36")["fields"]["SPINUP_TIME"], "500 ms");
+    }
+
+    #[test]
+    fn json_parses_back_to_the_snapshot() {
+        let snapshot = recorded();
+        let json = json(&decode(&snapshot));
+        assert_eq!(json, RECORDED.trim_end());
+        assert_eq!(Snapshot::parse(&json).unwrap(), snapshot);
+    }
+}
@@ -0,0 +1,1182 @@
+//! Register level access to the Microchip EMC2301 fan controller and its
+//! EMC2302, EMC2303 and EMC2305 multi-fan siblings
+
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fmt,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use rppal::i2c::{I2c, Result};
+use serde::Deserialize;
+
+use crate::{bus, driver::FanDriver};
+
+/// Configuration register
+pub const CONFIGURATION: u8 = 0x20;
+/// Fan Status register
+pub const FAN_STATUS: u8 = 0x24;
+/// Fan Stall Status register
+pub const FAN_STALL_STATUS: u8 = 0x25;
+/// Fan Spin Status register
+pub const FAN_SPIN_STATUS: u8 = 0x26;
+/// Drive Fail Status register
+pub const DRIVE_FAIL_STATUS: u8 = 0x27;
+/// Fan Interrupt Enable register
+pub const FAN_INTERRUPT_ENABLE: u8 = 0x29;
+/// PWM Polarity Config register
+pub const PWM_POLARITY: u8 = 0x2A;
+/// PWM Output Config register
+pub const PWM_OUTPUT: u8 = 0x2B;
+/// PWM Base Frequency register of fans 4 and 5
+pub const PWM_BASE45: u8 = 0x2C;
+/// PWM Base Frequency register of fans 1 to 3
+pub const PWM_BASE: u8 = 0x2D;
+/// Fan Setting register, the direct drive duty. This and the following
+/// registers up to TACH Reading low byte repeat for each fan.
+pub const FAN_SETTING: u8 = 0x30;
+/// PWM Divide register
+pub const PWM_DIVIDE: u8 = 0x31;
+/// Fan Configuration 1 register
+pub const FAN_CONFIG1: u8 = 0x32;
+/// Fan Configuration 2 register
+pub const FAN_CONFIG2: u8 = 0x33;
+/// Gain register of the Fan Speed Control algorithm
+pub const GAIN: u8 = 0x35;
+/// Fan Spin Up Configuration register
+pub const FAN_SPIN_UP: u8 = 0x36;
+/// Fan Max Step register
+pub const FAN_MAX_STEP: u8 = 0x37;
+/// Fan Minimum Drive register
+pub const FAN_MIN_DRIVE: u8 = 0x38;
+/// Valid TACH Count register
+pub const VALID_TACH_COUNT: u8 = 0x39;
+/// Fan Drive Fail Band low byte register
+pub const DRIVE_FAIL_BAND_LOW: u8 = 0x3A;
+/// Fan Drive Fail Band high byte register
+pub const DRIVE_FAIL_BAND_HIGH: u8 = 0x3B;
+/// TACH Target low byte register
+pub const TACH_TARGET_LOW: u8 = 0x3C;
+/// TACH Target high byte register
+pub const TACH_TARGET_HIGH: u8 = 0x3D;
+/// TACH Reading high byte register
+pub const TACH_READING_HIGH: u8 = 0x3E;
+/// TACH Reading low byte register
+pub const TACH_READING_LOW: u8 = 0x3F;
+/// Software Lock register
+pub const SOFTWARE_LOCK: u8 = 0xEF;
+/// Product ID register
+pub const PRODUCT_ID: u8 = 0xFD;
+/// Manufacturer ID register
+pub const MANUFACTURER_ID: u8 = 0xFE;
+/// Revision register
+pub const REVISION: u8 = 0xFF;
+
+/// Manufacturer ID of Microchip (formerly SMSC)
+pub const MICROCHIP_ID: u8 = 0x5D;
+
+/// Fan Status bit set when the watchdog timer expired
+const STATUS_WATCH: u8 = 0x80;
+/// Fan Status bit set on a drive failure
+const STATUS_DRIVE_FAIL: u8 = 0x04;
+/// Fan Status bit set on a spin-up failure
+const STATUS_FAN_SPIN: u8 = 0x02;
+/// Fan Status bit set on a stall
+const STATUS_FAN_STALL: u8 = 0x01;
+/// Configuration bit running the watchdog continuously
+const WD_EN: u8 = 0x20;
+/// Time without a Fan Setting or TACH Target write before the watchdog drives the fans at full speed
+pub const WATCHDOG_TIMEOUT_MS: u64 = 4000;
+/// Software Lock bit making the configuration registers read-only until power off
+const SWL: u8 = 0x01;
+/// Registers shared by all fans that the Software Lock freezes
+const LOCKED_REGISTERS: [u8; 6] = [
+    CONFIGURATION,
+    FAN_INTERRUPT_ENABLE,
+    PWM_POLARITY,
+    PWM_OUTPUT,
+    PWM_BASE45,
+    PWM_BASE,
+];
+/// Fan block registers that the Software Lock freezes, at the addresses of the first fan
+const LOCKED_FAN_REGISTERS: [u8; 10] = [
+    PWM_DIVIDE,
+    FAN_CONFIG1,
+    FAN_CONFIG2,
+    GAIN,
+    FAN_SPIN_UP,
+    FAN_MAX_STEP,
+    FAN_MIN_DRIVE,
+    VALID_TACH_COUNT,
+    DRIVE_FAIL_BAND_LOW,
+    DRIVE_FAIL_BAND_HIGH,
+];
+/// Offset between the register blocks of consecutive fans
+const FAN_BLOCK: u8 = 0x10;
+/// Fan Configuration 1 bit enabling the RPM based Fan Speed Control
+const EN_ALGO: u8 = 0x80;
+/// RANGE and EDGES fields of Fan Configuration 1
+const TACH_FIELDS: u8 = 0x78;
+/// Fan Configuration 2 bit applying Fan Max Step to direct drive writes too
+const EN_RRC: u8 = 0x40;
+/// Largest drive change per update Fan Max Step holds
+const MAX_STEP_LIMIT: u8 = 0x3F;
+/// Fan Configuration 1 power-on default
+const FAN_CONFIG1_DEFAULT: u8 = 0x2B;
+/// Frequency of the clock used to count tach pulses
+const TACH_CLOCK: f32 = 32768.0;
+/// Largest TACH count, reported when the fan is stopped
+pub const TACH_COUNT_MAX: u16 = 0x1FFF;
+/// Valid TACH Count with which a stall is never reported
+const VALID_TACH_OFF: u8 = 0xFF;
+
+/// Address of a fan block register for the fan at `fan`, 0 based
+pub fn fan_register(fan: u8, reg: u8) -> u8 {
+    reg + FAN_BLOCK * fan
+}
+
+/// Byte wide access to the controller registers
+pub trait Registers {
+    /// Read one register
+    fn read(&mut self, reg: u8) -> Result<u8>;
+    /// Write one register
+    fn write(&mut self, reg: u8, value: u8) -> Result<()>;
+}
+
+impl Registers for I2c {
+    fn read(&mut self, reg: u8) -> Result<u8> {
+        self.smbus_read_byte(reg)
+    }
+
+    fn write(&mut self, reg: u8, value: u8) -> Result<()> {
+        self.smbus_write_byte(reg, value)
+    }
+}
+
+/// The PWM base frequencies selectable in PWM Base Frequency
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum PwmBase {
+    #[default]
+    #[serde(rename = "26khz")]
+    F26000,
+    #[serde(rename = "19.5khz")]
+    F19531,
+    #[serde(rename = "4.9khz")]
+    F4882,
+    #[serde(rename = "2.4khz")]
+    F2441,
+}
+
+impl PwmBase {
+    /// The PWM Base Frequency register value
+    pub fn bits(&self) -> u8 {
+        match self {
+            PwmBase::F26000 => 0,
+            PwmBase::F19531 => 1,
+            PwmBase::F4882 => 2,
+            PwmBase::F2441 => 3,
+        }
+    }
+
+    /// Decode the PWM Base Frequency register
+    pub fn from_bits(value: u8) -> Self {
+        match value & 0x03 {
+            0 => PwmBase::F26000,
+            1 => PwmBase::F19531,
+            2 => PwmBase::F4882,
+            _ => PwmBase::F2441,
+        }
+    }
+
+    /// The base frequency in Hz
+    pub fn hz(&self) -> f32 {
+        match self {
+            PwmBase::F26000 => 26000.0,
+            PwmBase::F19531 => 19531.0,
+            PwmBase::F4882 => 4882.0,
+            PwmBase::F2441 => 2441.0,
+        }
+    }
+}
+
+/// The register and bit shift holding the PWM base frequency of a fan
+pub fn pwm_base_field(fan: u8) -> (u8, u8) {
+    if fan < 3 {
+        (PWM_BASE, 2 * fan)
+    } else {
+        (PWM_BASE45, 2 * (fan - 3))
+    }
+}
+
+/// Set the PWM output frequency of a fan to `base` divided by `divide`
+pub fn set_pwm_frequency(
+    regs: &mut dyn Registers,
+    fan: u8,
+    base: PwmBase,
+    divide: u8,
+) -> Result<()> {
+    let (reg, shift) = pwm_base_field(fan);
+    let value = regs.read(reg)? & !(0x03 << shift);
+    regs.write(reg, value | (base.bits() << shift))?;
+    regs.write(fan_register(fan, PWM_DIVIDE), divide)
+}
+
+/// Logic of the PWM signal the fan expects
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PwmPolarity {
+    /// Higher duty cycle runs the fan faster
+    #[default]
+    Normal,
+    /// Higher duty cycle runs the fan slower
+    Inverted,
+}
+
+impl PwmPolarity {
+    /// The duty cycle producing the logical `speed`
+    pub fn duty(&self, speed: u8) -> u8 {
+        match self {
+            PwmPolarity::Normal => speed,
+            PwmPolarity::Inverted => u8::MAX - speed,
+        }
+    }
+}
+
+/// Electrical type of the PWM output pin
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PwmOutput {
+    /// Driven low only, the fan pulls the line up
+    #[default]
+    OpenDrain,
+    /// Driven both high and low
+    PushPull,
+}
+
+/// Set the PWM polarity and output type of a fan.
+/// The chip inverts the output itself, the Fan Setting stays the logical speed.
+pub fn set_pwm_output(
+    regs: &mut dyn Registers,
+    fan: u8,
+    polarity: PwmPolarity,
+    output: PwmOutput,
+) -> Result<()> {
+    let bit = 1 << fan;
+    let value = regs.read(PWM_POLARITY)? & !bit;
+    let inverted = polarity == PwmPolarity::Inverted;
+    regs.write(PWM_POLARITY, if inverted { value | bit } else { value })?;
+    let value = regs.read(PWM_OUTPUT)? & !bit;
+    let push_pull = output == PwmOutput::PushPull;
+    regs.write(PWM_OUTPUT, if push_pull { value | bit } else { value })
+}
+
+/// Fan Spin Up Configuration settings
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpinUp {
+    /// Drive level held during spin-up, percent (30 to 65 in steps of 5)
+    pub level: u8,
+    /// Spin-up duration in ms (250, 500, 1000 or 2000)
+    pub time_ms: u16,
+    /// Update periods at full drive before a drive failure (0, 16, 32 or 64)
+    pub drive_fail_count: u8,
+    /// Drive at 100% for the first quarter of the spin-up
+    pub kick: bool,
+}
+
+impl SpinUp {
+    /// The Fan Spin Up Configuration value, `None` for unsupported settings
+    pub fn bits(&self) -> Option<u8> {
+        if !(30..=65).contains(&self.level) || !self.level.is_multiple_of(5) {
+            return None;
+        }
+        let level = (self.level - 30) / 5;
+        let time = match self.time_ms {
+            250 => 0,
+            500 => 1,
+            1000 => 2,
+            2000 => 3,
+            _ => return None,
+        };
+        let drive_fail = match self.drive_fail_count {
+            0 => 0,
+            16 => 1,
+            32 => 2,
+            64 => 3,
+            _ => return None,
+        };
+        let no_kick = if self.kick { 0 } else { 1 };
+        Some((drive_fail << 6) | (no_kick << 5) | (level << 2) | time)
+    }
+}
+
+/// Write the Fan Spin Up Configuration, unsupported settings are left untouched
+pub fn set_spin_up(regs: &mut dyn Registers, fan: u8, spin_up: &SpinUp) -> Result<()> {
+    match spin_up.bits() {
+        Some(bits) => regs.write(fan_register(fan, FAN_SPIN_UP), bits),
+        None => Ok(()),
+    }
+}
+
+/// Tachometer settings decoded from Fan Configuration 1
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TachConfig {
+    /// Tach edges sampled per count (3, 5, 7 or 9)
+    pub edges: u8,
+    /// Tach count multiplier selected by RANGE (1, 2, 4 or 8)
+    pub multiplier: u8,
+    /// Fan poles, tach pulses per revolution
+    pub poles: u8,
+}
+
+impl TachConfig {
+    /// Decode the RANGE and EDGES fields of Fan Configuration 1
+    pub fn from_fan_config1(value: u8) -> Self {
+        let edges = 3 + 2 * ((value >> 3) & 0x03);
+        Self {
+            edges,
+            multiplier: 1 << ((value >> 5) & 0x03),
+            poles: (edges - 1) / 2,
+        }
+    }
+
+    /// Settings for a fan giving `pulses_per_rev` tach pulses per revolution (1 to 4), with
+    /// the finest RANGE that still measures `slowest_rpm`
+    pub fn new(pulses_per_rev: u8, slowest_rpm: u32) -> Self {
+        let poles = pulses_per_rev.clamp(1, 4);
+        let edges = 2 * poles + 1;
+        [8, 4, 2, 1]
+            .into_iter()
+            .map(|multiplier| Self {
+                edges,
+                multiplier,
+                poles,
+            })
+            .find(|config| config.rpm(TACH_COUNT_MAX - 1) <= slowest_rpm)
+            .unwrap_or(Self {
+                edges,
+                multiplier: 1,
+                poles,
+            })
+    }
+
+    /// The RANGE and EDGES fields of Fan Configuration 1
+    pub fn bits(&self) -> u8 {
+        let range = self.multiplier.trailing_zeros() as u8;
+        let edges = (self.edges - 3) / 2;
+        (range << 5) | (edges << 3)
+    }
+
+    /// Convert a TACH count into revolutions per minute
+    pub fn rpm(&self, count: u16) -> u32 {
+        if count == 0 || count >= TACH_COUNT_MAX {
+            return 0;
+        }
+        let revs = f32::from(self.edges - 1) / f32::from(self.poles);
+        (revs * f32::from(self.multiplier) * TACH_CLOCK * 60.0 / f32::from(count)) as u32
+    }
+
+    /// Convert revolutions per minute into a TACH count
+    pub fn count(&self, rpm: u32) -> u16 {
+        if rpm == 0 {
+            return TACH_COUNT_MAX;
+        }
+        let revs = f32::from(self.edges - 1) / f32::from(self.poles);
+        let count = revs * f32::from(self.multiplier) * TACH_CLOCK * 60.0 / rpm as f32;
+        (count as u16).min(TACH_COUNT_MAX)
+    }
+}
+
+impl Default for TachConfig {
+    fn default() -> Self {
+        Self::from_fan_config1(FAN_CONFIG1_DEFAULT)
+    }
+}
+
+/// Split a TACH count into its (high, low) register bytes
+pub fn tach_to_bytes(count: u16) -> (u8, u8) {
+    ((count >> 5) as u8, ((count & 0x1F) << 3) as u8)
+}
+
+/// Join (high, low) register bytes into a TACH count
+pub fn tach_from_bytes(high: u8, low: u8) -> u16 {
+    (u16::from(high) << 5) | (u16::from(low) >> 3)
+}
+
+/// Read the tachometer configuration of a fan
+pub fn tach_config(regs: &mut dyn Registers, fan: u8) -> Result<TachConfig> {
+    Ok(TachConfig::from_fan_config1(
+        regs.read(fan_register(fan, FAN_CONFIG1))?,
+    ))
+}
+
+/// Set the RANGE and EDGES fields of a fan
+pub fn set_tach_config(regs: &mut dyn Registers, fan: u8, config: &TachConfig) -> Result<()> {
+    let reg = fan_register(fan, FAN_CONFIG1);
+    let value = regs.read(reg)?;
+    regs.write(reg, (value & !TACH_FIELDS) | config.bits())
+}
+
+/// Time between drive updates selected by the UPDATE field of Fan Configuration 1
+pub fn update_time_ms(fan_config1: u8) -> u32 {
+    [100, 200, 300, 400, 500, 800, 1200, 1600][usize::from(fan_config1 & 0x07)]
+}
+
+/// The Fan Max Step moving the drive of a fan by `rate` percent per second
+pub fn ramp_step(regs: &mut dyn Registers, fan: u8, rate: u8) -> Result<u8> {
+    let update_ms = update_time_ms(regs.read(fan_register(fan, FAN_CONFIG1))?);
+    let step = u32::from(rate) * 255 * update_ms / 100_000;
+    Ok(step.clamp(1, MAX_STEP_LIMIT.into()) as u8)
+}
+
+/// Limit the drive change of a fan at each update to `step`, `None` lifts the limit
+pub fn set_ramp_rate(regs: &mut dyn Registers, fan: u8, step: Option<u8>) -> Result<()> {
+    let reg = fan_register(fan, FAN_CONFIG2);
+    let config = regs.read(reg)?;
+    let (step, config) = match step {
+        Some(step) => (step.min(MAX_STEP_LIMIT), config | EN_RRC),
+        None => (MAX_STEP_LIMIT, config & !EN_RRC),
+    };
+    regs.write(fan_register(fan, FAN_MAX_STEP), step)?;
+    regs.write(reg, config)
+}
+
+/// Read the measured TACH count of a fan
+pub fn tach_count(regs: &mut dyn Registers, fan: u8) -> Result<u16> {
+    // The high byte latches the low byte, so it must be read first
+    let high = regs.read(fan_register(fan, TACH_READING_HIGH))?;
+    let low = regs.read(fan_register(fan, TACH_READING_LOW))?;
+    Ok(tach_from_bytes(high, low))
+}
+
+/// Read the speed of a fan in revolutions per minute
+pub fn fan_rpm(regs: &mut dyn Registers, fan: u8) -> Result<u32> {
+    let config = tach_config(regs, fan)?;
+    Ok(config.rpm(tach_count(regs, fan)?))
+}
+
+/// Read the drive of a fan, set directly or by the Fan Speed Control
+pub fn fan_setting(regs: &mut dyn Registers, fan: u8) -> Result<u8> {
+    regs.read(fan_register(fan, FAN_SETTING))
+}
+
+/// Set the direct drive duty of a fan
+pub fn set_fan_setting(regs: &mut dyn Registers, fan: u8, duty: u8) -> Result<()> {
+    regs.write(fan_register(fan, FAN_SETTING), duty)
+}
+
+/// Set the lowest drive the Fan Speed Control algorithm uses
+pub fn set_min_drive(regs: &mut dyn Registers, fan: u8, drive: u8) -> Result<()> {
+    regs.write(fan_register(fan, FAN_MIN_DRIVE), drive)
+}
+
+/// Set the slowest valid speed, below it the fan is stalled, 0 for the slowest speed the
+/// tach range measures and `None` to leave stalls unreported. Returns the speed the
+/// controller actually uses, limited by the tach range.
+pub fn set_valid_tach(
+    regs: &mut dyn Registers,
+    fan: u8,
+    config: &TachConfig,
+    rpm: Option<u32>,
+) -> Result<Option<u32>> {
+    // Only the high byte of the count is configurable, its highest value turns stalls off
+    let high = match rpm {
+        Some(rpm) => tach_to_bytes(config.count(rpm)).0.min(VALID_TACH_OFF - 1),
+        None => VALID_TACH_OFF,
+    };
+    regs.write(fan_register(fan, VALID_TACH_COUNT), high)?;
+    Ok(rpm.map(|_| config.rpm(tach_from_bytes(high, 0))))
+}
+
+/// Enable or disable the RPM based Fan Speed Control algorithm of a fan
+pub fn enable_algorithm(regs: &mut dyn Registers, fan: u8, enable: bool) -> Result<()> {
+    let reg = fan_register(fan, FAN_CONFIG1);
+    let config = regs.read(reg)?;
+    let config = if enable {
+        config | EN_ALGO
+    } else {
+        config & !EN_ALGO
+    };
+    regs.write(reg, config)
+}
+
+/// Let the faults of a fan assert ALERT#, or keep them to the status registers
+pub fn enable_fan_interrupt(regs: &mut dyn Registers, fan: u8, enable: bool) -> Result<()> {
+    let mask = regs.read(FAN_INTERRUPT_ENABLE)?;
+    let mask = if enable {
+        mask | 1 << fan
+    } else {
+        mask & !(1 << fan)
+    };
+    regs.write(FAN_INTERRUPT_ENABLE, mask)
+}
+
+/// Run the watchdog in continuous mode, or stop it.
+/// Writing the Fan Setting or TACH Target of any fan refreshes it.
+pub fn enable_watchdog(regs: &mut dyn Registers, enable: bool) -> Result<()> {
+    let config = regs.read(CONFIGURATION)?;
+    let config = if enable {
+        config | WD_EN
+    } else {
+        config & !WD_EN
+    };
+    regs.write(CONFIGURATION, config)
+}
+
+/// The watchdog runs in continuous mode
+pub fn watchdog_enabled(regs: &mut dyn Registers) -> Result<bool> {
+    Ok(regs.read(CONFIGURATION)? & WD_EN != 0)
+}
+
+/// The configuration registers are read-only until power off
+pub fn locked(regs: &mut dyn Registers) -> Result<bool> {
+    Ok(regs.read(SOFTWARE_LOCK)? & SWL != 0)
+}
+
+/// Make the configuration registers read-only until power off.
+/// Fan Setting and TACH Target stay writable.
+pub fn lock(regs: &mut dyn Registers) -> Result<()> {
+    regs.write(SOFTWARE_LOCK, SWL)
+}
+
+/// Register access that keeps the values written, to check them against the chip
+pub struct Recorder<'a> {
+    regs: &'a mut dyn Registers,
+    writes: BTreeMap<u8, u8>,
+}
+
+impl<'a> Recorder<'a> {
+    pub fn new(regs: &'a mut dyn Registers) -> Self {
+        Self {
+            regs,
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// The written registers holding another value, as (register, value, written value)
+    pub fn mismatches(&mut self) -> Result<Vec<(u8, u8, u8)>> {
+        mismatches(self.regs, &self.writes)
+    }
+
+    /// The registers written
+    pub fn registers(&self) -> Vec<u8> {
+        self.writes.keys().copied().collect()
+    }
+
+    /// The values written, by register
+    pub fn writes(&self) -> &BTreeMap<u8, u8> {
+        &self.writes
+    }
+}
+
+/// Read the value of each register
+pub fn read_back(regs: &mut dyn Registers, registers: &[u8]) -> Result<BTreeMap<u8, u8>> {
+    registers
+        .iter()
+        .map(|&reg| Ok((reg, regs.read(reg)?)))
+        .collect()
+}
+
+/// The registers holding another value than `expected`, as (register, value, expected value)
+pub fn mismatches(
+    regs: &mut dyn Registers,
+    expected: &BTreeMap<u8, u8>,
+) -> Result<Vec<(u8, u8, u8)>> {
+    let mut mismatches = Vec::new();
+    for (&reg, &wanted) in expected {
+        let value = regs.read(reg)?;
+        if value != wanted {
+            mismatches.push((reg, value, wanted));
+        }
+    }
+    Ok(mismatches)
+}
+
+impl Registers for Recorder<'_> {
+    fn read(&mut self, reg: u8) -> Result<u8> {
+        // Later read-modify-writes build on the earlier writes even when the chip ignores them
+        match self.writes.get(&reg) {
+            Some(&value) => Ok(value),
+            None => self.regs.read(reg),
+        }
+    }
+
+    fn write(&mut self, reg: u8, value: u8) -> Result<()> {
+        self.writes.insert(reg, value);
+        self.regs.write(reg, value)
+    }
+}
+
+/// Set the TACH count the Fan Speed Control algorithm holds, 0 RPM stops the fan
+pub fn set_target_rpm(
+    regs: &mut dyn Registers,
+    fan: u8,
+    config: &TachConfig,
+    rpm: u32,
+) -> Result<()> {
+    let (high, low) = tach_to_bytes(config.count(rpm));
+    // The target is latched when the high byte is written
+    regs.write(fan_register(fan, TACH_TARGET_LOW), low)?;
+    regs.write(fan_register(fan, TACH_TARGET_HIGH), high)
+}
+
+/// The members of the EMC230x fan controller family
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Product {
+    Emc2301,
+    Emc2302,
+    Emc2303,
+    Emc2305,
+}
+
+impl Product {
+    /// The product matching a Product ID register value
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0x37 => Some(Product::Emc2301),
+            0x36 => Some(Product::Emc2302),
+            0x35 => Some(Product::Emc2303),
+            0x34 => Some(Product::Emc2305),
+            _ => None,
+        }
+    }
+
+    /// Number of fans the product drives
+    pub fn fans(&self) -> u8 {
+        match self {
+            Product::Emc2301 => 1,
+            Product::Emc2302 => 2,
+            Product::Emc2303 => 3,
+            Product::Emc2305 => 5,
+        }
+    }
+
+    /// The smallest product driving `fans` fans
+    pub fn with_fans(fans: u8) -> Self {
+        match fans {
+            0 | 1 => Product::Emc2301,
+            2 => Product::Emc2302,
+            3 => Product::Emc2303,
+            _ => Product::Emc2305,
+        }
+    }
+
+    /// The Product ID register value
+    pub fn id(&self) -> u8 {
+        match self {
+            Product::Emc2301 => 0x37,
+            Product::Emc2302 => 0x36,
+            Product::Emc2303 => 0x35,
+            Product::Emc2305 => 0x34,
+        }
+    }
+}
+
+impl fmt::Display for Product {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Product::Emc2301 => write!(f, "EMC2301"),
+            Product::Emc2302 => write!(f, "EMC2302"),
+            Product::Emc2303 => write!(f, "EMC2303"),
+            Product::Emc2305 => write!(f, "EMC2305"),
+        }
+    }
+}
+
+/// The identification registers of the device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChipId {
+    pub product: u8,
+    pub manufacturer: u8,
+    pub revision: u8,
+}
+
+impl ChipId {
+    /// The EMC230x product, if the device is one
+    pub fn product(&self) -> Option<Product> {
+        if self.manufacturer != MICROCHIP_ID {
+            return None;
+        }
+        Product::from_id(self.product)
+    }
+}
+
+impl fmt::Display for ChipId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.product() {
+            Some(product) => write!(f, "{product}")?,
+            None => write!(f, "unknown device")?,
+        }
+        write!(
+            f,
+            " (product {:#04x}, manufacturer {:#04x}, revision {:#04x})",
+            self.product, self.manufacturer, self.revision
+        )
+    }
+}
+
+/// Read the identification registers
+pub fn read_chip_id(regs: &mut dyn Registers) -> Result<ChipId> {
+    Ok(ChipId {
+        product: regs.read(PRODUCT_ID)?,
+        manufacturer: regs.read(MANUFACTURER_ID)?,
+        revision: regs.read(REVISION)?,
+    })
+}
+
+/// A fault reported by the controller status registers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FanFault {
+    /// The tach count went above the valid TACH count while driving
+    Stall,
+    /// The fan did not reach speed at the end of the spin-up
+    SpinUpFailure,
+    /// The Fan Speed Control cannot reach the target at full drive
+    DriveFail,
+}
+
+impl fmt::Display for FanFault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FanFault::Stall => write!(f, "Fan stalled"),
+            FanFault::SpinUpFailure => write!(f, "Fan failed to spin up"),
+            FanFault::DriveFail => write!(f, "Fan cannot reach target speed at full drive"),
+        }
+    }
+}
+
+/// The Fan Status and the per fan Stall, Spin and Drive Fail registers
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Status {
+    pub status: u8,
+    pub stall: u8,
+    pub spin: u8,
+    pub drive_fail: u8,
+}
+
+impl Status {
+    /// The watchdog expired and drove the fans at full speed
+    pub fn watchdog(&self) -> bool {
+        self.status & STATUS_WATCH != 0
+    }
+
+    /// The faults of the fan at `fan`, 0 based
+    pub fn faults(&self, fan: u8) -> Vec<FanFault> {
+        let bit = 1 << fan;
+        let mut faults = Vec::new();
+        if self.status & STATUS_FAN_STALL != 0 && self.stall & bit != 0 {
+            faults.push(FanFault::Stall);
+        }
+        if self.status & STATUS_FAN_SPIN != 0 && self.spin & bit != 0 {
+            faults.push(FanFault::SpinUpFailure);
+        }
+        if self.status & STATUS_DRIVE_FAIL != 0 && self.drive_fail & bit != 0 {
+            faults.push(FanFault::DriveFail);
+        }
+        faults
+    }
+}
+
+/// Read the status registers of all fans, reading clears them
+pub fn read_status(regs: &mut dyn Registers) -> Result<Status> {
+    let status = regs.read(FAN_STATUS)?;
+    if status == 0 {
+        return Ok(Status::default());
+    }
+    Ok(Status {
+        status,
+        stall: regs.read(FAN_STALL_STATUS)?,
+        spin: regs.read(FAN_SPIN_STATUS)?,
+        drive_fail: regs.read(DRIVE_FAIL_STATUS)?,
+    })
+}
+
+/// Fans driven by an EMC230x, or by the Fan Setting alone of an unidentified chip
+pub struct Emc230x {
+    regs: Box<dyn Registers>,
+    product: Option<Product>,
+    bus: u8,
+    address: u16,
+}
+
+impl Emc230x {
+    /// Driver for the chip at `address` on `bus`, `product` when it was identified
+    pub fn new(regs: Box<dyn Registers>, product: Option<Product>, bus: u8, address: u16) -> Self {
+        Self {
+            regs,
+            product,
+            bus,
+            address,
+        }
+    }
+}
+
+impl fmt::Display for Emc230x {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "slave address {} in I2c bus: {}", self.address, self.bus)
+    }
+}
+
+impl FanDriver for Emc230x {
+    fn set_duty(&mut self, fan: u8, duty: u8) -> std::result::Result<(), Box<dyn Error>> {
+        set_fan_setting(self.regs.as_mut(), fan, duty)
+            .map_err(|err| bus::device_error(self.bus, self.address, err))?;
+        Ok(())
+    }
+
+    fn rpm(&mut self, fan: u8) -> Option<u32> {
+        fan_rpm(self.registers()?, fan).ok()
+    }
+
+    fn registers(&mut self) -> Option<&mut dyn Registers> {
+        match self.product {
+            Some(_) => Some(self.regs.as_mut()),
+            None => None,
+        }
+    }
+}
+
+/// In memory register map that behaves like an EMC230x driving its fans
+pub struct SimulatedRegisters {
+    regs: [u8; 256],
+    product: Product,
+    max_rpm: u32,
+    /// Set to power the chip off and on before its next access
+    power_cycle: Arc<AtomicBool>,
+}
+
+impl SimulatedRegisters {
+    /// Power-on register map of `product` with fans spinning at most at `max_rpm`
+    pub fn new(product: Product, max_rpm: u32) -> Self {
+        let mut sim = Self {
+            regs: [0; 256],
+            product,
+            max_rpm,
+            power_cycle: Arc::default(),
+        };
+        sim.power_on();
+        sim
+    }
+
+    /// A flag powering the chip off and on when set, bringing back the power-on registers
+    pub fn power_cycle_flag(&self) -> Arc<AtomicBool> {
+        self.power_cycle.clone()
+    }
+
+    /// Load the power-on register map
+    fn power_on(&mut self) {
+        self.regs = [0; 256];
+        self.regs[usize::from(CONFIGURATION)] = 0x40;
+        self.regs[usize::from(PRODUCT_ID)] = self.product.id();
+        self.regs[usize::from(MANUFACTURER_ID)] = MICROCHIP_ID;
+        self.regs[usize::from(REVISION)] = 0x80;
+        for fan in 0..self.product.fans() {
+            for (reg, value) in [
+                (FAN_CONFIG1, FAN_CONFIG1_DEFAULT),
+                (FAN_CONFIG2, 0x28),
+                (GAIN, 0x2A),
+                (FAN_MAX_STEP, 0x10),
+                (PWM_DIVIDE, 0x01),
+                (FAN_SPIN_UP, 0x19),
+                (FAN_MIN_DRIVE, 0x66),
+                (VALID_TACH_COUNT, 0xF5),
+                (TACH_TARGET_LOW, 0xF8),
+                (TACH_TARGET_HIGH, 0xFF),
+            ] {
+                self.regs[usize::from(fan_register(fan, reg))] = value;
+            }
+            self.update_tach(fan);
+        }
+    }
+
+    /// Apply a pending power cycle
+    fn check_power(&mut self) {
+        if self.power_cycle.swap(false, Ordering::Relaxed) {
+            self.power_on();
+        }
+    }
+
+    /// Value of a fan block register
+    fn fan_reg(&self, fan: u8, reg: u8) -> u8 {
+        self.regs[usize::from(fan_register(fan, reg))]
+    }
+
+    /// Make the tach reading follow the fan setting, or the target in FSC mode
+    fn update_tach(&mut self, fan: u8) {
+        let fan_config = self.fan_reg(fan, FAN_CONFIG1);
+        let config = TachConfig::from_fan_config1(fan_config);
+        let count = if fan_config & EN_ALGO != 0 {
+            let target = tach_from_bytes(
+                self.fan_reg(fan, TACH_TARGET_HIGH),
+                self.fan_reg(fan, TACH_TARGET_LOW),
+            );
+            let rpm = config.rpm(target).min(self.max_rpm);
+            self.regs[usize::from(fan_register(fan, FAN_SETTING))] =
+                (rpm * 255 / self.max_rpm) as u8;
+            config.count(rpm)
+        } else {
+            let duty = u32::from(self.fan_reg(fan, FAN_SETTING));
+            config.count(self.max_rpm * duty / 255)
+        };
+        let (high, low) = tach_to_bytes(count);
+        self.regs[usize::from(fan_register(fan, TACH_READING_HIGH))] = high;
+        self.regs[usize::from(fan_register(fan, TACH_READING_LOW))] = low;
+    }
+}
+
+impl Registers for SimulatedRegisters {
+    fn read(&mut self, reg: u8) -> Result<u8> {
+        self.check_power();
+        let value = self.regs[usize::from(reg)];
+        // Status bits clear once read, like on the chip
+        if matches!(
+            reg,
+            FAN_STATUS | FAN_STALL_STATUS | FAN_SPIN_STATUS | DRIVE_FAIL_STATUS
+        ) {
+            self.regs[usize::from(reg)] = 0;
+        }
+        Ok(value)
+    }
+
+    fn write(&mut self, reg: u8, value: u8) -> Result<()> {
+        self.check_power();
+        let block = reg.wrapping_sub(FAN_SETTING);
+        let fan = block / FAN_BLOCK;
+        let in_block = block < FAN_BLOCK * self.product.fans();
+        let offset = FAN_SETTING + block % FAN_BLOCK;
+        if in_block && matches!(offset, TACH_READING_HIGH | TACH_READING_LOW) {
+            return Ok(());
+        }
+        // Like the chip, the lock holds until power off and freezes the configuration
+        let locked = self.regs[usize::from(SOFTWARE_LOCK)] & SWL != 0;
+        let frozen = reg == SOFTWARE_LOCK
+            || LOCKED_REGISTERS.contains(&reg)
+            || in_block && LOCKED_FAN_REGISTERS.contains(&offset);
+        if locked && frozen {
+            return Ok(());
+        }
+        self.regs[usize::from(reg)] = value;
+        if in_block && matches!(offset, FAN_SETTING | FAN_CONFIG1 | TACH_TARGET_HIGH) {
+            self.update_tach(fan);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tach_config_decodes_the_power_on_default() {
+        let tach = TachConfig::default();
+        assert_eq!((tach.edges, tach.multiplier, tach.poles), (5, 2, 2));
+        assert_eq!(tach.bits(), FAN_CONFIG1_DEFAULT & TACH_FIELDS);
+    }
+
+    /// The spin-up of the configuration defaults
+    fn spin_up() -> SpinUp {
+        crate::config::SpinUpConfig::default().chip()
+    }
+
+    #[test]
+    fn spin_up_defaults_encode_the_power_on_value() {
+        assert_eq!(spin_up().bits(), Some(0x19));
+    }
+
+    #[test]
+    fn spin_up_encodes_each_field() {
+        let bits = |spin_up: SpinUp| spin_up.bits().unwrap();
+        assert_eq!(
+            bits(SpinUp {
+                level: 30,
+                ..spin_up()
+            }),
+            0x01
+        );
+        assert_eq!(
+            bits(SpinUp {
+                level: 65,
+                ..spin_up()
+            }),
+            0x1D
+        );
+        assert_eq!(
+            bits(SpinUp {
+                time_ms: 250,
+                ..spin_up()
+            }),
+            0x18
+        );
+        assert_eq!(
+            bits(SpinUp {
+                time_ms: 2000,
+                ..spin_up()
+            }),
+            0x1B
+        );
+        let drive_fail = |count| {
+            bits(SpinUp {
+                drive_fail_count: count,
+                ..spin_up()
+            }) >> 6
+        };
+        assert_eq!([16, 32, 64].map(drive_fail), [1, 2, 3]);
+        // NOKICK
+        assert_eq!(
+            bits(SpinUp {
+                kick: false,
+                ..spin_up()
+            }),
+            0x19 | 0x20
+        );
+    }
+
+    #[test]
+    fn spin_up_rejects_unsupported_settings() {
+        for spin_up in [
+            SpinUp {
+                level: 25,
+                ..spin_up()
+            },
+            SpinUp {
+                level: 70,
+                ..spin_up()
+            },
+            SpinUp {
+                level: 42,
+                ..spin_up()
+            },
+            SpinUp {
+                time_ms: 750,
+                ..spin_up()
+            },
+            SpinUp {
+                drive_fail_count: 8,
+                ..spin_up()
+            },
+        ] {
+            assert_eq!(spin_up.bits(), None, "{spin_up:?}");
+        }
+    }
+
+    #[test]
+    fn set_spin_up_leaves_unsupported_settings_alone() {
+        let mut sim = SimulatedRegisters::new(Product::Emc2301, 5000);
+        let reg = fan_register(0, FAN_SPIN_UP);
+        set_spin_up(
+            &mut sim,
+            0,
+            &SpinUp {
+                time_ms: 750,
+                ..spin_up()
+            },
+        )
+        .unwrap();
+        assert_eq!(sim.read(reg).unwrap(), 0x19);
+        set_spin_up(
+            &mut sim,
+            0,
+            &SpinUp {
+                level: 35,
+                kick: false,
+                ..spin_up()
+            },
+        )
+        .unwrap();
+        assert_eq!(sim.read(reg).unwrap(), 0x25);
+    }
+
+    #[test]
+    fn tach_config_converts_between_rpm_and_count() {
+        let tach = TachConfig::default();
+        assert_eq!(tach.rpm(3840), 2048);
+        assert_eq!(tach.count(2048), 3840);
+        // A stopped fan reads the largest count
+        assert_eq!(tach.rpm(0), 0);
+        assert_eq!(tach.rpm(TACH_COUNT_MAX), 0);
+        assert_eq!(tach.count(0), TACH_COUNT_MAX);
+        // Too slow to measure
+        assert_eq!(tach.count(1), TACH_COUNT_MAX);
+    }
+
+    #[test]
+    fn tach_config_picks_the_range_measuring_the_slowest_speed() {
+        let tach = TachConfig::new(2, 2000);
+        assert_eq!((tach.edges, tach.multiplier, tach.poles), (5, 4, 2));
+        assert_eq!(tach.bits(), 0x48);
+        assert!(tach.rpm(TACH_COUNT_MAX - 1) <= 2000);
+        assert_eq!(TachConfig::from_fan_config1(tach.bits() | EN_ALGO), tach);
+
+        let tach = TachConfig::new(1, 500);
+        assert_eq!((tach.edges, tach.multiplier, tach.poles), (3, 1, 1));
+        assert_eq!(tach.bits(), 0x00);
+        // Slower than any range measures
+        assert_eq!(TachConfig::new(4, 100).multiplier, 1);
+    }
+
+    #[test]
+    fn tach_bytes_round_trip() {
+        assert_eq!(tach_to_bytes(TACH_COUNT_MAX), (0xFF, 0xF8));
+        assert_eq!(tach_to_bytes(3840), (0x78, 0x00));
+        assert_eq!(tach_from_bytes(0x61, 0xF0), 3134);
+        for count in 0..=TACH_COUNT_MAX {
+            let (high, low) = tach_to_bytes(count);
+            assert_eq!(tach_from_bytes(high, low), count);
+        }
+    }
+
+    #[test]
+    fn simulated_tach_follows_the_fan_setting() {
+        let mut sim = SimulatedRegisters::new(Product::Emc2301, 5000);
+        assert_eq!(fan_rpm(&mut sim, 0).unwrap(), 0);
+        set_fan_setting(&mut sim, 0, 255).unwrap();
+        // The count is whole, the speed is back within one count
+        assert_eq!(fan_rpm(&mut sim, 0).unwrap(), 5002);
+        set_fan_setting(&mut sim, 0, 128).unwrap();
+        assert_eq!(tach_count(&mut sim, 0).unwrap(), 3134);
+        assert_eq!(fan_rpm(&mut sim, 0).unwrap(), 2509);
+    }
+
+    #[test]
+    fn read_status_is_empty_without_fan_status() {
+        let mut sim = SimulatedRegisters::new(Product::Emc2302, 5000);
+        // Per fan bits are only reported under a Fan Status bit
+        sim.write(FAN_STALL_STATUS, 0x01).unwrap();
+        let status = read_status(&mut sim).unwrap();
+        assert_eq!(status, Status::default());
+        assert!(status.faults(0).is_empty());
+        assert!(!status.watchdog());
+    }
+
+    #[test]
+    fn read_status_decodes_the_faults_of_each_fan() {
+        let mut sim = SimulatedRegisters::new(Product::Emc2303, 5000);
+        sim.write(FAN_STATUS, STATUS_FAN_STALL | STATUS_DRIVE_FAIL)
+            .unwrap();
+        sim.write(FAN_STALL_STATUS, 0b001).unwrap();
+        sim.write(FAN_SPIN_STATUS, 0b010).unwrap();
+        sim.write(DRIVE_FAIL_STATUS, 0b101).unwrap();
+        let status = read_status(&mut sim).unwrap();
+        assert_eq!(status.faults(0), vec![FanFault::Stall, FanFault::DriveFail]);
+        // The spin bit is ignored while FAN_SPIN is clear in Fan Status
+        assert!(status.faults(1).is_empty());
+        assert_eq!(status.faults(2), vec![FanFault::DriveFail]);
+        assert!(!status.watchdog());
+    }
+
+    #[test]
+    fn read_status_clears_the_status_registers() {
+        let mut sim = SimulatedRegisters::new(Product::Emc2301, 5000);
+        sim.write(FAN_STATUS, STATUS_WATCH | STATUS_FAN_SPIN)
+            .unwrap();
+        sim.write(FAN_SPIN_STATUS, 0x01).unwrap();
+        let status = read_status(&mut sim).unwrap();
+        assert!(status.watchdog());
+        assert_eq!(status.faults(0), vec![FanFault::SpinUpFailure]);
+        assert_eq!(read_status(&mut sim).unwrap(), Status::default());
+    }
+}
@@ -0,0 +1,98 @@
+//! Response to the faults reported by the fan controller
+
+use crate::{config::FaultPolicy, emc2301::FanFault};
+
+/// What the control loop must do after a status poll
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultAction {
+    /// Keep following the curve
+    None,
+    /// Drive the fan at full speed for one period to restart it
+    Kick,
+    /// Drive the fan at full speed and report the alarm
+    Alarm,
+}
+
+/// Applies the configured fault policy over successive status polls
+pub struct FaultHandler {
+    policy: FaultPolicy,
+    retries: u32,
+    attempts: u32,
+}
+
+impl FaultHandler {
+    /// Handler for `policy`, allowing `retries` kicks before raising an alarm
+    pub fn new(policy: FaultPolicy, retries: u32) -> Self {
+        Self {
+            policy,
+            retries,
+            attempts: 0,
+        }
+    }
+
+    /// Decide the response to the faults read in one poll
+    pub fn handle(&mut self, faults: &[FanFault]) -> FaultAction {
+        if faults.is_empty() {
+            self.attempts = 0;
+            return FaultAction::None;
+        }
+        match self.policy {
+            FaultPolicy::Log => FaultAction::None,
+            FaultPolicy::Alarm => FaultAction::Alarm,
+            FaultPolicy::Retry if self.attempts < self.retries => {
+                self.attempts += 1;
+                FaultAction::Kick
+            }
+            FaultPolicy::Retry => FaultAction::Alarm,
+        }
+    }
+
+    /// Number of kicks since the fan last ran without faults
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STALL: &[FanFault] = &[FanFault::Stall];
+
+    #[test]
+    fn retry_kicks_then_raises_the_alarm() {
+        let mut handler = FaultHandler::new(FaultPolicy::Retry, 2);
+        assert_eq!(handler.handle(STALL), FaultAction::Kick);
+        assert_eq!(handler.attempts(), 1);
+        assert_eq!(handler.handle(STALL), FaultAction::Kick);
+        assert_eq!(handler.attempts(), 2);
+        assert_eq!(handler.handle(STALL), FaultAction::Alarm);
+        assert_eq!(handler.handle(STALL), FaultAction::Alarm);
+        assert_eq!(handler.attempts(), 2);
+    }
+
+    #[test]
+    fn retry_starts_over_once_the_fan_runs() {
+        let mut handler = FaultHandler::new(FaultPolicy::Retry, 1);
+        assert_eq!(handler.handle(STALL), FaultAction::Kick);
+        assert_eq!(handler.handle(STALL), FaultAction::Alarm);
+        assert_eq!(handler.handle(&[]), FaultAction::None);
+        assert_eq!(handler.attempts(), 0);
+        assert_eq!(handler.handle(STALL), FaultAction::Kick);
+    }
+
+    #[test]
+    fn retry_without_retries_raises_the_alarm() {
+        let mut handler = FaultHandler::new(FaultPolicy::Retry, 0);
+        assert_eq!(handler.handle(STALL), FaultAction::Alarm);
+    }
+
+    #[test]
+    fn log_and_alarm_policies() {
+        let mut log = FaultHandler::new(FaultPolicy::Log, 3);
+        assert_eq!(log.handle(&[FanFault::DriveFail]), FaultAction::None);
+        let mut alarm = FaultHandler::new(FaultPolicy::Alarm, 3);
+        assert_eq!(alarm.handle(&[FanFault::SpinUpFailure]), FaultAction::Alarm);
+        assert_eq!(alarm.handle(&[]), FaultAction::None);
+    }
+}
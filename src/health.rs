@@ -0,0 +1,372 @@
+//! Fan wear tracking from the measured RPM at each drive level
+
+use std::{
+    collections::VecDeque,
+    error::Error,
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Directory of the learned baselines
+const STATE_DIR: &str = "/var/lib/cm4_fan_control";
+/// Number of drive bands the RPM is tracked in
+const BANDS: usize = 8;
+/// Width of a drive band in Fan Setting counts
+const BAND_WIDTH: usize = 256 / BANDS;
+/// Samples of a band compared with its baseline
+const WINDOW: usize = 12;
+
+/// Health tracking settings of a fan
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HealthConfig {
+    /// Track the fan health
+    pub enabled: bool,
+    /// Directory keeping the baselines across restarts
+    pub state_dir: PathBuf,
+    /// Samples averaged into the baseline of a band
+    pub baseline_samples: usize,
+    /// Drop below the baseline RPM raising an alert, percent
+    pub drop_percent: u8,
+    /// Spread of the recent RPM raising an alert, percent of their mean
+    pub erratic_percent: u8,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            state_dir: PathBuf::from(STATE_DIR),
+            baseline_samples: 60,
+            drop_percent: 20,
+            erratic_percent: 15,
+        }
+    }
+}
+
+/// How a fan departs from its baseline
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Symptom {
+    /// Turning slower than it used to at the same drive
+    Slower,
+    /// Turning at an unsteady speed at a steady drive
+    Erratic,
+}
+
+/// A band where the fan departs from its baseline
+#[derive(Debug, Clone, PartialEq)]
+pub struct HealthAlert {
+    /// Drive band, each `BAND_WIDTH` Fan Setting counts wide
+    pub band: usize,
+    pub symptom: Symptom,
+    /// Baseline RPM of the band
+    pub baseline: f32,
+    /// Mean of the recent RPM
+    pub mean: f32,
+    /// Standard deviation of the recent RPM, percent of their mean
+    pub spread: f32,
+}
+
+impl fmt::Display for HealthAlert {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let low = self.band * BAND_WIDTH * 100 / 255;
+        let high = ((self.band + 1) * BAND_WIDTH - 1) * 100 / 255;
+        match self.symptom {
+            Symptom::Slower => write!(
+                f,
+                "at {low}-{high}% drive the fan runs at {:.0} RPM, {:.0}% below its {:.0} RPM baseline",
+                self.mean,
+                100.0 * (1.0 - self.mean / self.baseline),
+                self.baseline
+            ),
+            Symptom::Erratic => write!(
+                f,
+                "at {low}-{high}% drive the fan speed varies by {:.0}% around {:.0} RPM",
+                self.spread, self.mean
+            ),
+        }
+    }
+}
+
+/// The RPM measured in one drive band
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+struct Band {
+    /// Mean RPM learned over the first samples
+    baseline: Option<f32>,
+    /// Samples gathered towards the baseline
+    #[serde(skip)]
+    learning: Vec<u32>,
+    /// Latest samples
+    #[serde(skip)]
+    recent: VecDeque<u32>,
+}
+
+/// Mean and standard deviation of samples
+fn mean_deviation(samples: impl Iterator<Item = u32> + Clone) -> (f32, f32) {
+    let count = samples.clone().count().max(1) as f32;
+    let mean = samples.clone().map(|rpm| rpm as f32).sum::<f32>() / count;
+    let variance = samples.map(|rpm| (rpm as f32 - mean).powi(2)).sum::<f32>() / count;
+    (mean, variance.sqrt())
+}
+
+/// RPM samples of a fan by drive band, with the baselines learned
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct History {
+    bands: Vec<Band>,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self {
+            bands: vec![Band::default(); BANDS],
+        }
+    }
+}
+
+impl History {
+    /// Add a sample at steady `drive`. True when it completes the baseline of its band.
+    pub fn record(&mut self, drive: u8, rpm: u32, baseline_samples: usize) -> bool {
+        let band = &mut self.bands[usize::from(drive) / BAND_WIDTH];
+        band.recent.push_back(rpm);
+        if band.recent.len() > WINDOW {
+            band.recent.pop_front();
+        }
+        if band.baseline.is_some() {
+            return false;
+        }
+        band.learning.push(rpm);
+        if band.learning.len() < baseline_samples {
+            return false;
+        }
+        band.baseline = Some(mean_deviation(std::mem::take(&mut band.learning).into_iter()).0);
+        true
+    }
+
+    /// Bands where the recent samples drop or spread beyond the thresholds
+    pub fn analyze(&self, config: &HealthConfig) -> Vec<HealthAlert> {
+        let mut alerts = Vec::new();
+        for (index, band) in self.bands.iter().enumerate() {
+            let Some(baseline) = band.baseline else {
+                continue;
+            };
+            if band.recent.len() < WINDOW {
+                continue;
+            }
+            let (mean, deviation) = mean_deviation(band.recent.iter().copied());
+            let spread = if mean > 0.0 {
+                100.0 * deviation / mean
+            } else {
+                0.0
+            };
+            let alert = |symptom| HealthAlert {
+                band: index,
+                symptom,
+                baseline,
+                mean,
+                spread,
+            };
+            if mean < baseline * (1.0 - f32::from(config.drop_percent) / 100.0) {
+                alerts.push(alert(Symptom::Slower));
+            }
+            if spread > f32::from(config.erratic_percent) {
+                alerts.push(alert(Symptom::Erratic));
+            }
+        }
+        alerts
+    }
+}
+
+/// Health tracking of one fan, the baselines kept in a file
+pub struct FanHealth {
+    config: HealthConfig,
+    path: PathBuf,
+    history: History,
+    /// Alerts already reported, as (band, symptom)
+    active: Vec<(usize, Symptom)>,
+}
+
+impl FanHealth {
+    /// Tracking for fan `channel`, resuming from the baselines saved before
+    pub fn load(config: &HealthConfig, channel: u8) -> Self {
+        let path = config.state_dir.join(format!("fan{channel}_health.json"));
+        let history = match fs::read_to_string(&path) {
+            Ok(text) => serde_json::from_str::<History>(&text)
+                .map_err(|err| err.to_string())
+                .and_then(|history| match history.bands.len() {
+                    BANDS => Ok(history),
+                    len => Err(format!("{len} drive bands instead of {BANDS}")),
+                })
+                .unwrap_or_else(|err| {
+                    eprintln!("Ignoring the fan baseline in {}: {err}", path.display());
+                    History::default()
+                }),
+            Err(_) => History::default(),
+        };
+        Self {
+            config: config.clone(),
+            path,
+            history,
+            active: Vec::new(),
+        }
+    }
+
+    /// Record a steady sample, returning the alerts not reported yet
+    pub fn record(&mut self, drive: u8, rpm: u32) -> Vec<HealthAlert> {
+        if self
+            .history
+            .record(drive, rpm, self.config.baseline_samples)
+        {
+            if let Err(err) = save(&self.path, &self.history) {
+                eprintln!(
+                    "Unable to save the fan baseline in {}: {err}",
+                    self.path.display()
+                );
+            }
+        }
+        let alerts = self.history.analyze(&self.config);
+        let new = alerts
+            .iter()
+            .filter(|alert| !self.active.contains(&(alert.band, alert.symptom)))
+            .cloned()
+            .collect();
+        self.active = alerts
+            .iter()
+            .map(|alert| (alert.band, alert.symptom))
+            .collect();
+        new
+    }
+}
+
+/// Write the history, creating its directory
+fn save(path: &Path, history: &History) -> Result<(), Box<dyn Error>> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, serde_json::to_string_pretty(history)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempTree;
+
+    /// Fan Setting in band 4
+    const DRIVE: u8 = 128;
+
+    fn config() -> HealthConfig {
+        HealthConfig {
+            enabled: true,
+            baseline_samples: 5,
+            ..HealthConfig::default()
+        }
+    }
+
+    /// A history with a 3000 RPM baseline at `DRIVE`
+    fn learned() -> History {
+        let mut history = History::default();
+        for _ in 1..5 {
+            assert!(!history.record(DRIVE, 3000, 5));
+        }
+        assert!(history.record(DRIVE, 3000, 5));
+        history
+    }
+
+    /// The symptoms found after `samples` fill the recent window
+    fn symptoms(samples: &[u32]) -> Vec<Symptom> {
+        let mut history = learned();
+        for &rpm in samples.iter().cycle().take(WINDOW) {
+            history.record(DRIVE, rpm, 5);
+        }
+        history
+            .analyze(&config())
+            .iter()
+            .map(|alert| alert.symptom)
+            .collect()
+    }
+
+    #[test]
+    fn baseline_is_the_mean_of_the_first_samples() {
+        let mut history = History::default();
+        for rpm in [2900, 3100, 3000, 2950] {
+            assert!(!history.record(DRIVE, rpm, 5));
+        }
+        assert!(history.record(DRIVE, 3050, 5));
+        assert_eq!(history.bands[4].baseline, Some(3000.0));
+        assert!(history.bands[4].learning.is_empty());
+        // Later samples leave it alone, other bands learn their own
+        assert!(!history.record(DRIVE, 1000, 5));
+        assert_eq!(history.bands[4].baseline, Some(3000.0));
+        assert_eq!(history.bands[3].baseline, None);
+    }
+
+    #[test]
+    fn analysis_waits_for_a_full_window() {
+        let mut history = learned();
+        for _ in 5..WINDOW - 1 {
+            history.record(DRIVE, 1000, 5);
+        }
+        assert!(history.analyze(&config()).is_empty());
+    }
+
+    #[test]
+    fn slower_below_the_drop_threshold() {
+        assert_eq!(symptoms(&[2000]), vec![Symptom::Slower]);
+        assert_eq!(symptoms(&[2399]), vec![Symptom::Slower]);
+        // 20% below the baseline is still fine
+        assert!(symptoms(&[2400]).is_empty());
+    }
+
+    #[test]
+    fn erratic_beyond_the_spread_threshold() {
+        assert_eq!(symptoms(&[2500, 3500]), vec![Symptom::Erratic]);
+        assert!(symptoms(&[2600, 3400]).is_empty());
+        assert_eq!(
+            symptoms(&[1000, 2800]),
+            vec![Symptom::Slower, Symptom::Erratic]
+        );
+    }
+
+    #[test]
+    fn alerts_are_reported_once_until_they_clear() {
+        let tree = TempTree::new("health_alerts");
+        let config = HealthConfig {
+            state_dir: tree.path().join("state"),
+            ..config()
+        };
+        let mut health = FanHealth::load(&config, 1);
+        for _ in 0..5 {
+            assert!(health.record(DRIVE, 3000).is_empty());
+        }
+        assert!(tree.path().join("state/fan1_health.json").exists());
+        // The window mixing both speeds is erratic too, only the drop is counted
+        let reported = |health: &mut FanHealth, rpm| {
+            (0..WINDOW)
+                .flat_map(|_| health.record(DRIVE, rpm))
+                .filter(|alert| alert.symptom == Symptom::Slower)
+                .count()
+        };
+        assert_eq!(reported(&mut health, 2000), 1);
+        assert_eq!(reported(&mut health, 2000), 0);
+        assert_eq!(reported(&mut health, 3000), 0);
+        assert_eq!(reported(&mut health, 2000), 1);
+        // The baseline is resumed from the state file
+        let mut resumed = FanHealth::load(&config, 1);
+        assert_eq!(reported(&mut resumed, 2000), 1);
+    }
+
+    #[test]
+    fn short_state_file_is_ignored() {
+        let tree = TempTree::new("health_short");
+        tree.file("state/fan1_health.json", r#"{"bands": []}"#);
+        let config = HealthConfig {
+            state_dir: tree.path().join("state"),
+            ..config()
+        };
+        let mut health = FanHealth::load(&config, 1);
+        assert_eq!(health.history, History::default());
+        assert!(health.record(DRIVE, 3000).is_empty());
+    }
+}
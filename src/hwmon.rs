@@ -0,0 +1,288 @@
+//! Fans driven through the hwmon sysfs interface of an in-kernel driver
+
+use std::{
+    error::Error,
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{config::Config, driver::FanDriver};
+
+/// Directory of the hwmon devices under the sysfs root
+const HWMON_CLASS: &str = "class/hwmon";
+/// `pwmN_enable` value giving the duty to user space
+const PWM_MANUAL: &str = "1";
+
+/// The hwmon device whose `name` attribute is `name`, under the sysfs `root`. Several devices
+/// of that name are told apart by `parent`, the device their `device` link points to, like
+/// `nvme0` or `1-0048`; without it they are an error.
+pub fn find(root: &Path, name: &str, parent: Option<&str>) -> Result<PathBuf, Box<dyn Error>> {
+    let class = root.join(HWMON_CLASS);
+    let entries =
+        fs::read_dir(&class).map_err(|err| format!("Unable to list {}: {err}", class.display()))?;
+    let mut devices: Vec<PathBuf> = entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|device| {
+            fs::read_to_string(device.join("name")).is_ok_and(|found| found.trim() == name)
+        })
+        .filter(|device| parent.is_none() || device_of(device).as_deref() == parent)
+        .collect();
+    devices.sort();
+    match (devices.len(), parent) {
+        (1, _) => Ok(devices.remove(0)),
+        (0, None) => Err(format!("No hwmon device named {name} in {}", class.display()).into()),
+        (0, Some(parent)) => Err(format!(
+            "No hwmon device named {name} on {parent} in {}",
+            class.display()
+        )
+        .into()),
+        _ => {
+            let parents: Vec<String> = devices.iter().filter_map(|dev| device_of(dev)).collect();
+            Err(format!(
+                "Several hwmon devices named {name} in {}, on {}",
+                class.display(),
+                parents.join(", ")
+            )
+            .into())
+        }
+    }
+}
+
+/// The name of the device a hwmon device belongs to, from its `device` link
+fn device_of(hwmon: &Path) -> Option<String> {
+    let target = fs::read_link(hwmon.join("device")).ok()?;
+    Some(target.file_name()?.to_string_lossy().into_owned())
+}
+
+/// The `tempN_input` of a hwmon device whose `tempN_label` is `label`, `temp1_input` without one
+pub fn find_temp(device: &Path, label: Option<&str>) -> Result<PathBuf, Box<dyn Error>> {
+    let Some(label) = label else {
+        return Ok(device.join("temp1_input"));
+    };
+    let entries = fs::read_dir(device)
+        .map_err(|err| format!("Unable to list {}: {err}", device.display()))?;
+    let mut labels: Vec<PathBuf> = entries
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.starts_with("temp") && name.ends_with("_label"))
+        })
+        .collect();
+    labels.sort();
+    labels
+        .into_iter()
+        .find(|path| fs::read_to_string(path).is_ok_and(|found| found.trim() == label))
+        .and_then(|path| {
+            let name = path.file_name()?.to_str()?.replace("_label", "_input");
+            Some(path.with_file_name(name))
+        })
+        .ok_or_else(|| format!("No temperature labeled {label} in {}", device.display()).into())
+}
+
+/// Fans of a hwmon device, `pwmN` and `fanN_input` for fan channel N
+pub struct HwmonFans {
+    device: PathBuf,
+    /// `pwmN_enable` attributes with the mode found at startup, restored on exit
+    enables: Vec<(PathBuf, String)>,
+}
+
+impl HwmonFans {
+    /// Find the device and take manual control of every configured fan
+    pub fn open(config: &Config) -> Result<Self, Box<dyn Error>> {
+        let device = find(&config.fan.sysfs_root, &config.fan.hwmon_name, None)?;
+        let mut fans = Self {
+            device,
+            enables: Vec::new(),
+        };
+        for fan in config.channels() {
+            let pwm = fans.attribute("pwm", fan.channel, "");
+            if !pwm.exists() {
+                return Err(format!("Fan channel {} has no {}", fan.channel, pwm.display()).into());
+            }
+            // Drivers without the attribute are always in manual mode
+            let enable = fans.attribute("pwm", fan.channel, "_enable");
+            if let Ok(mode) = fs::read_to_string(&enable) {
+                write(&enable, PWM_MANUAL)?;
+                fans.enables.push((enable, mode.trim().to_string()));
+            }
+        }
+        Ok(fans)
+    }
+
+    /// Path of the attribute `{prefix}{channel}{suffix}`
+    fn attribute(&self, prefix: &str, channel: u8, suffix: &str) -> PathBuf {
+        self.device.join(format!("{prefix}{channel}{suffix}"))
+    }
+}
+
+/// Write a sysfs attribute
+fn write(path: &Path, value: &str) -> Result<(), Box<dyn Error>> {
+    fs::write(path, value).map_err(|err| format!("Unable to write {}: {err}", path.display()))?;
+    Ok(())
+}
+
+impl Drop for HwmonFans {
+    fn drop(&mut self) {
+        // Give the fans back to the driver the way they were found
+        for (enable, mode) in &self.enables {
+            if let Err(err) = write(enable, mode) {
+                eprintln!("{err}");
+            }
+        }
+    }
+}
+
+impl fmt::Display for HwmonFans {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "hwmon device {}", self.device.display())
+    }
+}
+
+impl FanDriver for HwmonFans {
+    fn set_duty(&mut self, fan: u8, duty: u8) -> Result<(), Box<dyn Error>> {
+        write(&self.attribute("pwm", fan + 1, ""), &duty.to_string())
+    }
+
+    fn rpm(&mut self, fan: u8) -> Option<u32> {
+        fs::read_to_string(self.attribute("fan", fan + 1, "_input"))
+            .ok()?
+            .trim()
+            .parse()
+            .ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::{Driver, FanConfig},
+        testing::TempTree,
+    };
+
+    /// A sysfs tree with a CPU sensor in hwmon0 and an emc2305 with one fan in hwmon1
+    fn sysfs(name: &str) -> TempTree {
+        let tree = TempTree::new(name);
+        tree.file("class/hwmon/hwmon0/name", "cpu_thermal\n");
+        tree.file("class/hwmon/hwmon0/temp1_input", "48300\n");
+        tree.file("class/hwmon/hwmon1/name", "emc2305\n");
+        tree.file("class/hwmon/hwmon1/pwm1", "0\n");
+        tree.file("class/hwmon/hwmon1/pwm1_enable", "2\n");
+        tree.file("class/hwmon/hwmon1/fan1_input", "3120\n");
+        tree
+    }
+
+    #[test]
+    fn find_matches_the_name_attribute() {
+        let tree = sysfs("hwmon_find");
+        let class = tree.path().join(HWMON_CLASS);
+        assert_eq!(
+            find(tree.path(), "emc2305", None).unwrap(),
+            class.join("hwmon1")
+        );
+        assert_eq!(
+            find(tree.path(), "cpu_thermal", None).unwrap(),
+            class.join("hwmon0")
+        );
+        let err = find(tree.path(), "nvme", None).unwrap_err().to_string();
+        assert_eq!(
+            err,
+            format!("No hwmon device named nvme in {}", class.display())
+        );
+        assert!(find(&tree.path().join("missing"), "emc2305", None).is_err());
+    }
+
+    #[test]
+    fn find_needs_the_device_of_same_named_chips() {
+        let tree = TempTree::new("hwmon_find_parent");
+        for (hwmon, drive) in [("hwmon2", "nvme1"), ("hwmon10", "nvme0")] {
+            tree.file(&format!("class/hwmon/{hwmon}/name"), "nvme\n");
+            let target = format!("../../../devices/virtual/nvme/{drive}");
+            tree.link(&target, &format!("class/hwmon/{hwmon}/device"));
+        }
+        let class = tree.path().join(HWMON_CLASS);
+        let err = find(tree.path(), "nvme", None).unwrap_err().to_string();
+        assert_eq!(
+            err,
+            format!(
+                "Several hwmon devices named nvme in {}, on nvme0, nvme1",
+                class.display()
+            )
+        );
+        assert_eq!(
+            find(tree.path(), "nvme", Some("nvme0")).unwrap(),
+            class.join("hwmon10")
+        );
+        assert_eq!(
+            find(tree.path(), "nvme", Some("nvme1")).unwrap(),
+            class.join("hwmon2")
+        );
+        let err = find(tree.path(), "nvme", Some("nvme2"))
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            err,
+            format!("No hwmon device named nvme on nvme2 in {}", class.display())
+        );
+    }
+
+    #[test]
+    fn find_temp_matches_the_label() {
+        let tree = TempTree::new("hwmon_find_temp");
+        let device = tree.path().join("hwmon2");
+        tree.file("hwmon2/temp1_label", "Composite\n");
+        tree.file("hwmon2/temp2_label", "Sensor 1\n");
+        tree.file("hwmon2/temp3_label", "Sensor 2\n");
+        assert_eq!(
+            find_temp(&device, None).unwrap(),
+            device.join("temp1_input")
+        );
+        assert_eq!(
+            find_temp(&device, Some("Sensor 1")).unwrap(),
+            device.join("temp2_input")
+        );
+        assert_eq!(
+            find_temp(&device, Some("Composite")).unwrap(),
+            device.join("temp1_input")
+        );
+        // Past temp9, whatever the order of the names
+        let input = tree.file("hwmon2/temp10_label", "Sensor 9\n");
+        assert_eq!(
+            find_temp(&device, Some("Sensor 9")).unwrap(),
+            input.with_file_name("temp10_input")
+        );
+        let err = find_temp(&device, Some("Sensor 3"))
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            err,
+            format!("No temperature labeled Sensor 3 in {}", device.display())
+        );
+        assert!(find_temp(&tree.path().join("hwmon3"), Some("Composite")).is_err());
+    }
+
+    #[test]
+    fn fans_are_driven_and_handed_back() {
+        let tree = sysfs("hwmon_fans");
+        let config = Config {
+            fan: FanConfig {
+                driver: Driver::Hwmon,
+                sysfs_root: tree.path().to_path_buf(),
+                ..FanConfig::default()
+            },
+            fans: Vec::new(),
+        };
+        let device = tree.path().join(HWMON_CLASS).join("hwmon1");
+        let read = |attribute: &str| fs::read_to_string(device.join(attribute)).unwrap();
+        let mut fans = HwmonFans::open(&config).unwrap();
+        assert_eq!(read("pwm1_enable"), PWM_MANUAL);
+        fans.set_duty(0, 128).unwrap();
+        assert_eq!(read("pwm1"), "128");
+        assert_eq!(fans.rpm(0), Some(3120));
+        drop(fans);
+        assert_eq!(read("pwm1_enable"), "2");
+    }
+}
@@ -1,122 +1,1150 @@
-use rppal::i2c::I2c;
-use std::f32::consts::PI;
-use tokio::fs;
+mod alert;
+mod ambient;
+mod bus;
+mod config;
+mod cooling;
+mod curve;
+mod driver;
+mod dump;
+mod emc2301;
+mod faults;
+mod health;
+mod hwmon;
+mod model;
+mod nvme;
+mod pwm;
+#[cfg(test)]
+mod testing;
+mod thermal;
+mod w1;
+
+use std::{
+    collections::BTreeMap, error::Error, path::PathBuf, process::ExitCode, sync::atomic::Ordering,
+};
+
+use alert::{AlertLine, GpioAlert, SimulatedAlert};
+use config::{Config, ControlMode, Driver, FanConfig, UnknownChip};
+use cooling::CoolingFans;
+use curve::MAX_SPEED;
+use driver::FanDriver;
+use dump::{Format, Snapshot};
+use emc2301::{Emc230x, Product, PwmPolarity, Registers, SimulatedRegisters, Status, TachConfig};
+use faults::{FaultAction, FaultHandler};
+use health::FanHealth;
+use hwmon::HwmonFans;
+use nvme::Nvme;
+use pwm::PwmFans;
+use thermal::Sensors;
 use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
 // use tokio::task;
-use tokio::time::{sleep, Duration};
+use tokio::time::{interval, interval_at, sleep, Duration, Instant, MissedTickBehavior};
 use tokio_util::sync::CancellationToken;
 
-/// Temperature below which to stop the fan
-const OFF_TEMP: f32 = 40.0;
-/// Temperature above which to start the fan
-const MIN_TEMP: f32 = 45.0;
-/// Temperature above which to reach full fan speed
-const MAX_TEMP: f32 = 75.0;
-
-/// The speed percentage that the fan is off at
-const FAN_OFF: f32 = 0.0;
-/// The speed percentage for lowest fan speed
-const FAN_LOW: f32 = 0.1;
-/// The speed percentage for full fan speed
-const FAN_MAX: f32 = 1.0;
-/// The slope of the fan speed vs temperature
-const FAN_GAIN: f32 = (FAN_MAX - FAN_LOW) / (MAX_TEMP - MIN_TEMP);
-/// The max speed setting
-const MAX_SPEED: f32 = 255.0;
-
 /// I2c fan control bus
 const I2C_BUS: u8 = 10;
 /// I2c fan control slave address
 const I2C_SLA: u16 = 0x2f;
-/// I2c fan control speed command
-const I2C_CMD: u8 = 0x30;
+
+/// Top speed of the fan used by the simulator
+const SIMULATED_MAX_RPM: u32 = 5000;
 
 /// Number of seconds between fan speed updates
 const UPDATE_PERIOD: u64 = 5;
+/// Time between software ramp steps in direct mode
+const RAMP_PERIOD_MS: u64 = 250;
+/// Time between watchdog refreshes, well inside the watchdog timeout
+const WATCHDOG_REFRESH_MS: u64 = emc2301::WATCHDOG_TIMEOUT_MS / 2;
 
-/// The fan percentage curve
+/// The target RPM for a fan speed setting in rpm mode
 #[inline]
-fn fan_curve(temp: f32) -> f32 {
-    (0.5 * (1.0 - ((PI * temp) / 50.0).sin())
-        + (FAN_LOW + ((temp - MIN_TEMP).min(MAX_TEMP) * FAN_GAIN)))
-        / 2.0
+fn target_rpm(config: &FanConfig, speed: u8) -> u32 {
+    config.max_rpm() * u32::from(speed) / MAX_SPEED as u32
 }
 
-/// The fan speed vs temperature
-#[inline]
-fn fan_speed(cpu_temp: f32) -> u8 {
-    let fan_percentage = match cpu_temp {
-        t if t < OFF_TEMP => FAN_OFF,
-        t if t < MIN_TEMP => FAN_LOW,
-        t if t < MAX_TEMP => fan_curve(t),
-        _ => FAN_MAX,
+/// The status line logged at each update
+fn status_line(temp: &str, speed: u8, target: Option<u32>, rpm: Option<u32>) -> String {
+    let mut line = format!("{temp}, Fan Speed: {speed}");
+    if let Some(target) = target {
+        line += &format!(", Target RPM: {target}");
+    }
+    match rpm {
+        Some(rpm) => line += &format!(", Fan RPM: {rpm}"),
+        None => line += ", Fan RPM: unknown",
+    }
+    line
+}
+
+/// The message of a failed `what` with the fan controller, and why
+fn controller_error(what: &str, err: rppal::i2c::Error) -> String {
+    bus::bus_error(what, I2C_BUS, I2C_SLA, err)
+}
+
+/// Open the fan controller registers, real or simulated with `fans` fans
+fn open_registers(simulate: bool, fans: u8) -> Result<Box<dyn Registers>, Box<dyn Error>> {
+    if simulate {
+        let product = Product::with_fans(fans);
+        return Ok(Box::new(SimulatedRegisters::new(
+            product,
+            SIMULATED_MAX_RPM,
+        )));
+    }
+    let i2c = bus::open(I2C_BUS, I2C_SLA).map_err(|err| err.to_string())?;
+    Ok(Box::new(i2c))
+}
+
+/// The simulated controller of the service, SIGUSR2 powers it off and on
+fn open_simulator(fans: u8) -> Result<Box<dyn Registers>, Box<dyn Error>> {
+    let sim = SimulatedRegisters::new(Product::with_fans(fans), SIMULATED_MAX_RPM);
+    let power_cycle = sim.power_cycle_flag();
+    let mut usr2 = signal(SignalKind::user_defined2())?;
+    tokio::spawn(async move {
+        while usr2.recv().await.is_some() {
+            power_cycle.store(true, Ordering::Relaxed);
+        }
+    });
+    Ok(Box::new(sim))
+}
+
+/// Identify the controller, `None` when it only gets direct duty writes
+fn identify_chip(
+    regs: &mut dyn Registers,
+    config: &Config,
+) -> Result<Option<Product>, Box<dyn Error>> {
+    let id = emc2301::read_chip_id(regs).map_err(|err| bus::device_error(I2C_BUS, I2C_SLA, err))?;
+    if let Some(product) = id.product() {
+        println!("Fan controller: {id}");
+        if config.max_channel() > product.fans() {
+            return Err(format!(
+                "Fan channel {} is configured but the {product} drives {} fans",
+                config.max_channel(),
+                product.fans()
+            )
+            .into());
+        }
+        return Ok(Some(product));
+    }
+    match config.fan.unknown_chip {
+        UnknownChip::Refuse => Err(format!("Fan controller is not an EMC230x: {id}").into()),
+        UnknownChip::Direct if config.max_channel() > 1 => Err(format!(
+            "Fan controller is not an EMC230x: {id}, only fan channel 1 can be used"
+        )
+        .into()),
+        UnknownChip::Direct => {
+            eprintln!("Fan controller is not an EMC230x: {id}, using direct duty writes only");
+            Ok(None)
+        }
+    }
+}
+
+/// Open the configured fan driver, identifying the EMC230x
+fn open_driver(config: &Config, simulate: bool) -> Result<Box<dyn FanDriver>, Box<dyn Error>> {
+    match config.fan.driver {
+        Driver::Emc230x => {
+            let mut regs = if simulate {
+                println!("Simulating fan controller.");
+                open_simulator(config.max_channel())?
+            } else {
+                open_registers(false, config.max_channel())?
+            };
+            let product = identify_chip(regs.as_mut(), config)?;
+            Ok(Box::new(Emc230x::new(regs, product, I2C_BUS, I2C_SLA)))
+        }
+        _ if simulate => Err("Only the emc230x driver can be simulated".into()),
+        Driver::Pwm | Driver::Gpio => Ok(Box::new(PwmFans::open(config)?)),
+        Driver::Hwmon => {
+            let fans = HwmonFans::open(config)?;
+            println!("Fan driver: {fans}");
+            Ok(Box::new(fans))
+        }
+        Driver::Cooling => Ok(Box::new(CoolingFans::open(config)?)),
+    }
+}
+
+/// The control state of one fan channel
+struct Channel {
+    /// Fan index on the controller, from 0
+    fan: u8,
+    /// Prefix of the log lines, empty with a single fan
+    label: String,
+    config: FanConfig,
+    /// Temperature inputs the curve follows
+    sensors: Sensors,
+    /// NVMe drive temperature with its own curve
+    nvme: Option<Nvme>,
+    tach: TachConfig,
+    faults: FaultHandler,
+    /// Inversion applied to the duty before writing it, when the output does not invert
+    polarity: PwmPolarity,
+    /// Logical speed the curve asks for, whatever the polarity
+    last_speed: u8,
+    /// Logical speed driven now, behind `last_speed` while ramping
+    speed: u8,
+    /// Software ramp progress short of a whole speed step, in hundred-thousandths of a step
+    ramp_carry: u64,
+    /// A speed was written since startup or the last controller reset
+    driven: bool,
+    /// Fan Max Step of the chip ramp in rpm mode
+    ramp_step: Option<u8>,
+    /// The chip ramp is lifted for a rise to full speed
+    ramp_lifted: bool,
+    /// Registers the ramp lift wrote since the last poll, with their values
+    ramp_writes: BTreeMap<u8, u8>,
+    /// RPM baseline tracking, when enabled
+    health: Option<FanHealth>,
+}
+
+impl Channel {
+    fn new(config: FanConfig, sensors: Sensors, nvme: Option<Nvme>, multiple: bool) -> Self {
+        let fan = config.fan();
+        Self {
+            fan,
+            label: if multiple {
+                format!("Fan {}: ", fan + 1)
+            } else {
+                String::new()
+            },
+            tach: TachConfig::default(),
+            faults: FaultHandler::new(config.on_fault, config.spin_up_retries),
+            health: config
+                .health
+                .enabled
+                .then(|| FanHealth::load(&config.health, config.channel)),
+            config,
+            sensors,
+            nvme,
+            polarity: PwmPolarity::Normal,
+            last_speed: 255,
+            driven: false,
+            speed: 0,
+            ramp_carry: 0,
+            ramp_step: None,
+            ramp_lifted: false,
+            ramp_writes: BTreeMap::new(),
+        }
+    }
+
+    /// Apply the PWM settings and select the control mode on the controller
+    fn setup(&mut self, regs: &mut dyn Registers) -> rppal::i2c::Result<()> {
+        let (fan, config) = (self.fan, &self.config);
+        emc2301::set_pwm_frequency(regs, fan, config.pwm_frequency, config.pwm_divide)?;
+        emc2301::set_pwm_output(regs, fan, config.pwm_polarity, config.pwm_output)?;
+        emc2301::set_spin_up(regs, fan, &config.spin_up.chip())?;
+        self.tach = TachConfig::new(config.pulses_per_rev(), config.slowest_rpm());
+        emc2301::set_tach_config(regs, fan, &self.tach)?;
+        emc2301::set_min_drive(regs, fan, (MAX_SPEED * config.min_drive()) as u8)?;
+        // Without tach the fan never reads as turning, so stalls go unreported
+        let min_rpm = config.has_tach().then_some(config.min_rpm);
+        let stall_rpm = emc2301::set_valid_tach(regs, fan, &self.tach, min_rpm)?;
+        let below_range = |rpm: &u32| config.min_rpm > 0 && *rpm > config.min_rpm;
+        if let Some(stall_rpm) = stall_rpm.filter(below_range) {
+            eprintln!(
+                "{}min_rpm {} is below the tach range, stalls are reported under {stall_rpm} RPM",
+                self.label, config.min_rpm
+            );
+        }
+        println!(
+            "{}PWM frequency: {:.0} Hz",
+            self.label,
+            config.pwm_frequency.hz() / f32::from(config.pwm_divide)
+        );
+        let rpm_mode = config.mode == ControlMode::Rpm;
+        if rpm_mode && config.ramp_rate > 0 {
+            let step = emc2301::ramp_step(regs, fan, config.ramp_rate)?;
+            emc2301::set_ramp_rate(regs, fan, Some(step))?;
+            self.ramp_step = Some(step);
+        }
+        if config.ramp_rate > 0 {
+            println!("{}Ramp rate: {}%/s", self.label, config.ramp_rate);
+        }
+        emc2301::enable_algorithm(regs, fan, rpm_mode)?;
+        if rpm_mode {
+            println!(
+                "{}Fan control in rpm mode, full speed is {} RPM.",
+                self.label,
+                config.max_rpm()
+            );
+        }
+        Ok(())
+    }
+
+    /// Drive the fan at `speed`, directly or through the target RPM
+    fn set_fan(&self, driver: &mut dyn FanDriver, speed: u8) -> Result<(), Box<dyn Error>> {
+        match self.config.mode {
+            ControlMode::Direct => driver.set_duty(self.fan, self.polarity.duty(speed)),
+            ControlMode::Rpm => {
+                let regs = driver.registers().ok_or("rpm mode needs an EMC230x")?;
+                let target = target_rpm(&self.config, speed);
+                emc2301::set_target_rpm(regs, self.fan, &self.tach, target)
+                    .map_err(|err| bus::device_error(I2C_BUS, I2C_SLA, err))?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Briefly drive a stopped fan at the spin-up level before settling at `speed`
+    async fn kick(&self, driver: &mut dyn FanDriver, speed: u8) {
+        let spin_up = &self.config.spin_up;
+        let kick = (MAX_SPEED * f32::from(spin_up.level) / 100.0) as u8;
+        if kick <= speed {
+            return;
+        }
+        if driver.set_duty(self.fan, self.polarity.duty(kick)).is_ok() {
+            println!(
+                "{}Kick-starting fan at {kick} for {} ms",
+                self.label, spin_up.time_ms
+            );
+            sleep(Duration::from_millis(spin_up.time_ms.into())).await;
+        }
+    }
+
+    /// Write the current speed again, refreshing the watchdog
+    fn refresh(&self, driver: &mut dyn FanDriver) -> Result<(), Box<dyn Error>> {
+        self.set_fan(driver, self.speed)
+    }
+
+    /// The speed is limited in software, in direct mode
+    fn software_ramp(&self) -> bool {
+        self.config.mode == ControlMode::Direct && self.config.ramp_rate > 0
+    }
+
+    /// The speed to drive after `elapsed_ms` of software ramp towards `last_speed`
+    fn slew(&mut self, elapsed_ms: u64) -> u8 {
+        let rate = u64::from(self.config.ramp_rate);
+        let (from, to) = (self.speed, self.last_speed);
+        // Full speed is never delayed, and a fan can't ramp through a stop
+        if !self.software_ramp() || to == MAX_SPEED as u8 || from == 0 || to == 0 {
+            self.ramp_carry = 0;
+            return to;
+        }
+        // Slow rates move less than a step per tick, the remainder adds up over the ticks
+        let moved = rate * 255 * elapsed_ms + self.ramp_carry;
+        self.ramp_carry = moved % 100_000;
+        let step = (moved / 100_000).min(255) as u8;
+        let speed = if to > from {
+            to.min(from.saturating_add(step))
+        } else {
+            to.max(from.saturating_sub(step))
+        };
+        if speed == to {
+            self.ramp_carry = 0;
+        }
+        speed
+    }
+
+    /// The software ramp has not reached the curve speed yet
+    fn ramping(&self) -> bool {
+        self.software_ramp() && self.speed != 0 && self.speed != self.last_speed
+    }
+
+    /// Take one software ramp step
+    fn ramp(&mut self, driver: &mut dyn FanDriver) -> Result<(), Box<dyn Error>> {
+        let speed = self.slew(RAMP_PERIOD_MS);
+        self.set_fan(driver, speed)?;
+        self.speed = speed;
+        Ok(())
+    }
+
+    /// Lift the chip ramp for a rise to full speed, restore it afterwards
+    fn lift_ramp(&mut self, driver: &mut dyn FanDriver) -> Result<(), Box<dyn Error>> {
+        let (Some(step), Some(regs)) = (self.ramp_step, driver.registers()) else {
+            return Ok(());
+        };
+        let lift = self.last_speed == MAX_SPEED as u8;
+        if lift != self.ramp_lifted {
+            // Kept so the read-back takes them for the configuration, not for a reset
+            let mut recorder = emc2301::Recorder::new(regs);
+            emc2301::set_ramp_rate(&mut recorder, self.fan, (!lift).then_some(step))?;
+            self.ramp_writes.extend(recorder.writes());
+            self.ramp_lifted = lift;
+        }
+        Ok(())
+    }
+
+    /// The Fan Setting the chip holds once written, `None` in rpm mode where it moves
+    fn duty(&self) -> Option<u8> {
+        (self.config.mode == ControlMode::Direct).then(|| self.polarity.duty(self.speed))
+    }
+
+    /// Forget the speed driven, so the next update writes it again
+    fn restart(&mut self, locked: bool) {
+        self.last_speed = 255;
+        self.speed = 0;
+        self.ramp_carry = 0;
+        self.driven = false;
+        self.ramp_lifted = false;
+        if locked {
+            // The chip ramp can't be lifted any more
+            self.ramp_step = None;
+        }
+    }
+
+    /// Compare the RPM at a steady drive with the fan baseline
+    fn check_health(&mut self, driver: &mut dyn FanDriver) {
+        if self.speed == 0 || self.ramping() {
+            return;
+        }
+        let Some(health) = self.health.as_mut() else {
+            return;
+        };
+        let Some(regs) = driver.registers() else {
+            return;
+        };
+        let (Ok(drive), Ok(rpm)) = (
+            emc2301::fan_setting(regs, self.fan),
+            emc2301::fan_rpm(regs, self.fan),
+        ) else {
+            return;
+        };
+        for alert in health.record(drive, rpm) {
+            eprintln!("{}Fan health: {alert}", self.label);
+        }
+    }
+
+    /// Respond to the faults and follow the curve for one update period
+    async fn update(&mut self, driver: &mut dyn FanDriver, status: &Status) -> Result<(), String> {
+        let faults = if self.config.has_tach() {
+            status.faults(self.fan)
+        } else {
+            Vec::new()
+        };
+        for fault in &faults {
+            eprintln!("{}Fan fault: {fault}", self.label);
+        }
+        let action = self.faults.handle(&faults);
+        match action {
+            FaultAction::None => {}
+            FaultAction::Kick => eprintln!(
+                "{}Kicking fan at full speed, attempt {} of {}",
+                self.label,
+                self.faults.attempts(),
+                self.config.spin_up_retries
+            ),
+            FaultAction::Alarm => {
+                eprintln!("{}Fan alarm: fan failing, holding full speed", self.label)
+            }
+        }
+        let temp = self
+            .sensors
+            .read()
+            .await
+            .map_err(|err| format!("{}{err}", self.label))?;
+        for err in &temp.errors {
+            eprintln!("{}{err}", self.label);
+        }
+        let mut temps = temp.to_string();
+        let mut curve_speed = self.config.curve.speed(temp.value, self.config.min_drive());
+        if let Some(nvme) = self.nvme.as_mut() {
+            match nvme.read() {
+                Ok(drive_temp) => {
+                    temps += &format!(", NVMe Temp: {drive_temp:.2}°C");
+                    // The fan follows whichever asks for more
+                    let drive_speed = nvme.curve.speed(drive_temp, self.config.min_drive());
+                    curve_speed = curve_speed.max(drive_speed);
+                }
+                // The zones alone are followed until the drive reads again
+                Err(err) => {
+                    eprintln!("{}{err}", self.label);
+                    temps += ", NVMe Temp: unknown";
+                }
+            }
+        }
+        let new_speed = match action {
+            FaultAction::None => curve_speed,
+            FaultAction::Kick | FaultAction::Alarm => MAX_SPEED as u8,
+        };
+        // Read before the new speed is written, so a fan stuck at a steady drive shows up
+        let rpm = driver.rpm(self.fan).filter(|_| self.config.has_tach());
+        let target =
+            (self.config.mode == ControlMode::Rpm).then(|| target_rpm(&self.config, new_speed));
+        println!(
+            "{}{}",
+            self.label,
+            status_line(&temps, new_speed, target, rpm)
+        );
+        // Full speed matches the initial `last_speed`, it must still be written once
+        if new_speed == self.last_speed && self.driven {
+            if action == FaultAction::None {
+                self.check_health(driver);
+            }
+            return Ok(());
+        }
+        let software_kick =
+            self.config.mode == ControlMode::Direct && self.config.spin_up.software_kick;
+        if software_kick && self.speed == 0 && new_speed > 0 {
+            self.kick(driver, new_speed).await;
+        }
+        self.last_speed = new_speed;
+        let speed = self.slew(RAMP_PERIOD_MS);
+        if let Err(err) = self
+            .lift_ramp(driver)
+            .and_then(|_| self.set_fan(driver, speed))
+        {
+            return Err(format!(
+                "{}Unable to set fan speed on {driver}: {err}",
+                self.label
+            ));
+        }
+        self.speed = speed;
+        self.driven = true;
+        Ok(())
+    }
+}
+
+/// Open the ALERT# line when one is configured. Simulated, SIGUSR1 asserts it.
+fn open_alert(
+    config: &Config,
+    simulate: bool,
+) -> Result<Option<Box<dyn AlertLine>>, Box<dyn Error>> {
+    let Some(pin) = config.fan.alert_pin else {
+        return Ok(None);
+    };
+    if !simulate {
+        return Ok(Some(Box::new(GpioAlert::open(pin)?)));
+    }
+    let line = SimulatedAlert::default();
+    let mut usr1 = signal(SignalKind::user_defined1())?;
+    let trigger = line.clone();
+    tokio::spawn(async move {
+        while usr1.recv().await.is_some() {
+            trigger.trigger();
+        }
+    });
+    Ok(Some(Box::new(line)))
+}
+
+/// Read the fan status once and update every fan, `applied` takes the registers they rewrote
+async fn poll(
+    driver: &mut dyn FanDriver,
+    channels: &mut [Channel],
+    applied: &mut BTreeMap<u8, u8>,
+) -> Result<(), String> {
+    let status = match driver.registers() {
+        Some(regs) => {
+            emc2301::read_status(regs).map_err(|err| controller_error("read fan status", err))?
+        }
+        None => Status::default(),
     };
-    (MAX_SPEED * fan_percentage).floor() as u8
+    if status.watchdog() {
+        eprintln!("Watchdog expired, fans driven at full speed");
+    }
+    for channel in channels {
+        channel.update(driver, &status).await?;
+        applied.append(&mut channel.ramp_writes);
+    }
+    Ok(())
+}
+
+/// Start the watchdog, then lock the configuration when asked.
+/// On a chip locked before, the watchdog runs only if the lock kept it enabled.
+fn start_watchdog(regs: &mut dyn Registers, lock: bool, locked: &mut bool) -> Result<bool, String> {
+    if *locked {
+        let enabled = emc2301::watchdog_enabled(regs)
+            .map_err(|err| controller_error("read the watchdog", err))?;
+        if enabled {
+            println!("Watchdog enabled by the locked configuration.");
+        } else {
+            eprintln!("Watchdog disabled by the locked configuration, it can't be started.");
+        }
+        return Ok(enabled);
+    }
+    emc2301::enable_watchdog(regs, true)
+        .map_err(|err| controller_error("enable the watchdog", err))?;
+    println!(
+        "Watchdog enabled, fans go to full speed if not refreshed in {} ms",
+        emc2301::WATCHDOG_TIMEOUT_MS
+    );
+    if lock {
+        emc2301::lock(regs).map_err(|err| controller_error("lock the configuration", err))?;
+        *locked = true;
+        println!("Fan controller configuration locked until power off.");
+    }
+    Ok(true)
 }
 
-/// The temperature of the cpu in degrees Celsius
-async fn get_cpu_temp() -> Result<f32, std::io::Error> {
-    let temp_unparsed = fs::read_to_string("/sys/class/thermal/thermal_zone0/temp").await?;
-    Ok(temp_unparsed.trim().parse::<f32>().unwrap_or(45000.0) / 1000.0)
+/// Apply the settings of every fan to the EMC230x, and report what a lock keeps different.
+/// Returns the registers written.
+fn configure(
+    regs: &mut dyn Registers,
+    channels: &mut [Channel],
+    alert: bool,
+    locked: bool,
+) -> Result<Vec<u8>, String> {
+    // Writes to a locked chip are ignored, the recorder finds what differs
+    let mut recorder = emc2301::Recorder::new(regs);
+    for channel in channels.iter_mut() {
+        channel.setup(&mut recorder).map_err(|err| {
+            format!(
+                "{}{}",
+                channel.label,
+                controller_error("configure fan", err)
+            )
+        })?;
+        if alert && channel.config.has_tach() {
+            emc2301::enable_fan_interrupt(&mut recorder, channel.fan, true).map_err(|err| {
+                format!(
+                    "{}{}",
+                    channel.label,
+                    controller_error("enable the fan alert", err)
+                )
+            })?;
+        }
+    }
+    if !locked {
+        return Ok(recorder.registers());
+    }
+    let read_back = |err| controller_error("read back the configuration", err);
+    let mismatches = recorder.mismatches().map_err(read_back)?;
+    let id = emc2301::read_chip_id(&mut recorder).map_err(read_back)?;
+    let fans = id.product().map_or(1, |product| product.fans());
+    if mismatches.is_empty() {
+        println!("Fan controller configuration is locked and matches the settings.");
+    }
+    for (reg, value, wanted) in mismatches {
+        eprintln!(
+            "Fan controller configuration is locked with {} at {value:#04x}, configured {wanted:#04x}",
+            dump::register_name(reg, fans)
+        );
+    }
+    let registers = recorder.registers();
+    // The RPM conversions must use the RANGE and EDGES the locked chip kept
+    for channel in channels {
+        channel.tach = emc2301::tach_config(regs, channel.fan).map_err(read_back)?;
+    }
+    Ok(registers)
+}
+
+/// Read the registers the service set, a chip reset brings them back to their defaults
+fn read_applied(regs: &mut dyn Registers, registers: &[u8]) -> Result<BTreeMap<u8, u8>, String> {
+    emc2301::read_back(regs, registers)
+        .map_err(|err| controller_error("read back the configuration", err))
 }
 
-/// Update fan speed each PERIOD seconds
-async fn fan_handle(cancel: CancellationToken) {
-    let mut last_speed: u8 = 255;
-    let bus = I2c::with_bus(I2C_BUS);
-    if bus.is_err() {
-        eprintln!("Unable to open I2c bus: {I2C_BUS}");
-        return;
+/// Compare the controller with the configuration applied and the drive of each fan.
+/// Returns what differs.
+fn check_controller(
+    regs: &mut dyn Registers,
+    applied: &BTreeMap<u8, u8>,
+    channels: &[Channel],
+    fans: u8,
+) -> Result<Vec<String>, String> {
+    let fail = |err| controller_error("read back the configuration", err);
+    let mut changes: Vec<String> = emc2301::mismatches(regs, applied)
+        .map_err(fail)?
+        .into_iter()
+        .map(|(reg, value, wanted)| {
+            format!(
+                "{} at {value:#04x} instead of {wanted:#04x}",
+                dump::register_name(reg, fans)
+            )
+        })
+        .collect();
+    for channel in channels {
+        let Some(duty) = channel.duty() else {
+            continue;
+        };
+        let setting = emc2301::fan_setting(regs, channel.fan).map_err(fail)?;
+        if setting != duty {
+            changes.push(format!(
+                "{} at {setting:#04x} instead of {duty:#04x}",
+                dump::register_name(
+                    emc2301::fan_register(channel.fan, emc2301::FAN_SETTING),
+                    fans
+                )
+            ));
+        }
     }
-    let mut i2c = bus.unwrap();
-    let address = i2c.set_slave_address(I2C_SLA);
-    if address.is_err() {
-        eprintln!("Unable to set slave address {I2C_SLA} in I2c bus: {I2C_BUS}");
-        return;
+    Ok(changes)
+}
+
+/// Apply the whole configuration again after a controller reset, then have every fan take
+/// its speed anew. Returns the registers written.
+fn reconfigure(
+    regs: &mut dyn Registers,
+    channels: &mut [Channel],
+    alert: bool,
+    lock: bool,
+    locked: &mut bool,
+    watchdog: &mut bool,
+) -> Result<Vec<u8>, String> {
+    *locked =
+        emc2301::locked(regs).map_err(|err| controller_error("read the software lock", err))?;
+    let mut written = configure(regs, channels, alert, *locked)?;
+    if *watchdog {
+        *watchdog = start_watchdog(regs, lock, locked)?;
+        written.extend([emc2301::CONFIGURATION, emc2301::SOFTWARE_LOCK]);
+    }
+    for channel in channels {
+        channel.restart(*locked);
+    }
+    Ok(written)
+}
+
+/// Update fan speed each PERIOD seconds, until cancelled or an error stops it
+async fn fan_handle(
+    cancel: CancellationToken,
+    config: Config,
+    simulate: bool,
+) -> Result<(), String> {
+    let mut driver = open_driver(&config, simulate).map_err(|err| err.to_string())?;
+    let mut locked = false;
+    if let Some(regs) = driver.registers() {
+        locked =
+            emc2301::locked(regs).map_err(|err| controller_error("read the software lock", err))?;
+    }
+    let multiple = !config.fans.is_empty();
+    let mut channels = Vec::new();
+    for fan in config.channels() {
+        let root = &config.fan.sysfs_root;
+        let nvme = fan
+            .nvme
+            .as_ref()
+            .map(|nvme| Nvme::open(root, nvme, simulate))
+            .transpose();
+        let (sensors, nvme) = Sensors::open(root, fan, simulate)
+            .and_then(|sensors| Ok((sensors, nvme?)))
+            .map_err(|err| format!("Fan channel {}: {err}", fan.channel))?;
+        channels.push(Channel::new(fan.clone(), sensors, nvme, multiple));
+    }
+    // The line stays open as long as the loop runs, an alert triggers an early poll
+    let (alert_tx, mut alerts) = mpsc::unbounded_channel();
+    let mut line = if driver.registers().is_some() {
+        open_alert(&config, simulate).map_err(|err| err.to_string())?
+    } else {
+        None
+    };
+    // The registers set on the controller, read back to detect a reset
+    let mut written = Vec::new();
+    let mut applied = BTreeMap::new();
+    match driver.registers() {
+        Some(regs) => {
+            (applied, written) = configure(regs, &mut channels, line.is_some(), locked)
+                .and_then(|registers| Ok((read_applied(regs, &registers)?, registers)))?;
+        }
+        None => {
+            for channel in &mut channels {
+                channel.config.mode = ControlMode::Direct;
+                // The PWM drivers invert themselves, cooling states have no polarity
+                if matches!(config.fan.driver, Driver::Emc230x | Driver::Hwmon) {
+                    channel.polarity = channel.config.pwm_polarity;
+                }
+            }
+        }
     }
+    if let Some(line) = line.as_mut() {
+        line.watch(Box::new(move || {
+            let _ = alert_tx.send(());
+        }))
+        .map_err(|err| format!("Unable to watch ALERT#: {err}"))?;
+        println!("Watching ALERT# for fan faults.");
+    }
+    let period = Duration::from_secs(UPDATE_PERIOD);
+    let mut update = interval_at(Instant::now() + period, period);
+    let mut refresh = interval(Duration::from_millis(WATCHDOG_REFRESH_MS));
+    let mut ramp = interval(Duration::from_millis(RAMP_PERIOD_MS));
+    ramp.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // Started once every fan got its first speed, so it never sees a stale setting
+    let mut started = false;
+    let mut watchdog = false;
+    let fans = config.max_channel();
     loop {
         tokio::select! {
-            _ = sleep(Duration::from_secs(UPDATE_PERIOD)) => {
-                if let Ok(temp) = get_cpu_temp().await {
-                    let new_speed = fan_speed(temp);
-                    if new_speed != last_speed {
-                        if i2c.smbus_write_byte(I2C_CMD, new_speed).is_err() {
-                            eprintln!("Unable to set fan speed on slave address {I2C_SLA} in I2c bus: {I2C_BUS}");
-                            break;
-                        } else {
-                            last_speed = new_speed;
-                            println!("Cpu Temp: {temp:.2}°C, Fan Speed: {new_speed}");
+            _ = update.tick() => {
+                if let (true, Some(regs)) = (started, driver.registers()) {
+                    let changes = check_controller(regs, &applied, &channels, fans)?;
+                    if !changes.is_empty() {
+                        for change in &changes {
+                            eprintln!("Fan controller changed: {change}");
+                        }
+                        eprintln!("Fan controller reset, applying the configuration again.");
+                        let registers = reconfigure(
+                            regs,
+                            &mut channels,
+                            line.is_some(),
+                            config.fan.lock,
+                            &mut locked,
+                            &mut watchdog,
+                        )?;
+                        applied = read_applied(regs, &registers)?;
+                        written = registers;
+                    }
+                }
+                poll(driver.as_mut(), &mut channels, &mut applied).await?;
+                if let (false, Some(regs)) = (started, driver.registers()) {
+                    started = true;
+                    watchdog = start_watchdog(regs, config.fan.lock, &mut locked)?;
+                    if locked {
+                        // The chip ramp can't be lifted any more
+                        for channel in &mut channels {
+                            if channel.ramp_step.take().is_some() {
+                                eprintln!(
+                                    "{}Fan controller locked, rises to full speed stay limited \
+                                     by ramp_rate",
+                                    channel.label
+                                );
+                            }
                         }
                     }
-                } else {
-                    eprintln!("Missing cpu temperature measure!");
-                    break;
+                    written.extend([emc2301::CONFIGURATION, emc2301::SOFTWARE_LOCK]);
+                    applied = read_applied(regs, &written)?;
+                }
+            }
+            Some(()) = alerts.recv(), if line.is_some() => {
+                println!("Fan controller alert.");
+                poll(driver.as_mut(), &mut channels, &mut applied).await?;
+                // A kick started by the alert lasts a full period
+                update.reset();
+            }
+            _ = ramp.tick(), if channels.iter().any(Channel::ramping) => {
+                for channel in &mut channels {
+                    if !channel.ramping() {
+                        continue;
+                    }
+                    if let Err(err) = channel.ramp(driver.as_mut()) {
+                        return Err(format!(
+                            "{}Unable to set fan speed on {driver}: {err}",
+                            channel.label
+                        ));
+                    }
+                }
+            }
+            _ = refresh.tick(), if watchdog => {
+                for channel in &channels {
+                    if let Err(err) = channel.refresh(driver.as_mut()) {
+                        let err = format!("Unable to refresh the watchdog: {err}");
+                        return Err(format!("{}{err}", channel.label));
+                    }
                 }
             }
             _ = cancel.cancelled() => {
+                // A deliberate stop must not leave the watchdog to blast the fans
+                if watchdog && locked {
+                    eprintln!("Watchdog locked on, fans go to full speed.");
+                } else if let (true, Some(regs)) = (watchdog, driver.registers()) {
+                    if let Err(err) = emc2301::enable_watchdog(regs, false) {
+                        eprintln!("{}", controller_error("disable the watchdog", err));
+                    }
+                }
                 println!("Fan control stopped.");
                 break;
             }
         }
     }
+    Ok(())
+}
+
+/// Print every controller register decoded, from the bus or a recorded snapshot
+fn dump_registers(args: &Args) -> Result<(), Box<dyn Error>> {
+    let snapshot = match &args.snapshot {
+        Some(path) => Snapshot::load(path)?,
+        None => {
+            let fans = Config::load(&args.config)?.max_channel();
+            let mut regs = open_registers(args.simulate, fans)?;
+            Snapshot::read(regs.as_mut()).map_err(|err| bus::device_error(I2C_BUS, I2C_SLA, err))?
+        }
+    };
+    print!("{}", dump::render(&dump::decode(&snapshot), args.format));
+    Ok(())
+}
+
+/// What the program does
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Command {
+    /// Run the fan control service
+    Run,
+    /// Print the decoded controller registers
+    DumpRegisters,
+}
+
+/// Command line options
+struct Args {
+    /// What the program does
+    command: Command,
+    /// Path of the configuration file
+    config: PathBuf,
+    /// Run against a simulated fan controller
+    simulate: bool,
+    /// Output format of dump-registers
+    format: Format,
+    /// Recorded registers decoded by dump-registers instead of the controller
+    snapshot: Option<PathBuf>,
+}
+
+impl Args {
+    /// Parse the command line options
+    fn parse() -> Result<Self, Box<dyn Error>> {
+        let mut args = Args {
+            command: Command::Run,
+            config: PathBuf::from(config::CONFIG_PATH),
+            simulate: false,
+            format: Format::Table,
+            snapshot: None,
+        };
+        let mut iter = std::env::args().skip(1);
+        while let Some(arg) = iter.next() {
+            match arg.as_str() {
+                "dump-registers" => args.command = Command::DumpRegisters,
+                "--config" => {
+                    args.config = iter.next().ok_or("Missing path after --config")?.into();
+                }
+                "--simulate" => args.simulate = true,
+                "--format" => {
+                    let format = iter.next().ok_or("Missing format after --format")?;
+                    args.format = Format::parse(&format)
+                        .ok_or(format!("Unknown format {format}, use table or json"))?;
+                }
+                "--snapshot" => {
+                    args.snapshot =
+                        Some(iter.next().ok_or("Missing path after --snapshot")?.into());
+                }
+                _ => return Err(format!("Unknown argument: {arg}").into()),
+            }
+        }
+        Ok(args)
+    }
 }
 
 #[tokio::main(flavor = "current_thread")]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+async fn main() -> Result<ExitCode, Box<dyn Error>> {
+    let args = Args::parse()?;
+    if args.command == Command::DumpRegisters {
+        dump_registers(&args)?;
+        return Ok(ExitCode::SUCCESS);
+    }
+    let config = Config::load(&args.config)?;
     let mut sig = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
     let cancel = CancellationToken::new();
     let cloned_cancel = cancel.clone();
-    let mut fut = std::pin::pin!(fan_handle(cloned_cancel));
+    let mut fut = std::pin::pin!(fan_handle(cloned_cancel, config, args.simulate));
     loop {
         tokio::select! {
             _ = sig.recv() => {
                 cancel.cancel();
                 }
-            _ = &mut fut => {
+            _ = interrupt.recv() => {
+                cancel.cancel();
+            }
+            result = &mut fut => {
+                let code = match result {
+                    Ok(()) => ExitCode::SUCCESS,
+                    // A failure, for systemd to restart the service
+                    Err(err) => {
+                        eprintln!("{err}");
+                        ExitCode::FAILURE
+                    }
+                };
                 println!("Service stopped.");
-                break;
+                return Ok(code);
             }
         }
     }
-    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::*;
+    use crate::testing::TempTree;
+
+    /// An EMC2301 simulated in memory
+    fn simulated_driver() -> Emc230x {
+        let product = Product::Emc2301;
+        let sim = SimulatedRegisters::new(product, SIMULATED_MAX_RPM);
+        Emc230x::new(Box::new(sim), Some(product), I2C_BUS, I2C_SLA)
+    }
+
+    /// A single fan following `sensor`, without the software kick that would sleep
+    fn channel(config: FanConfig, sensor: PathBuf) -> Channel {
+        let mut config = FanConfig {
+            sensor: Some(sensor),
+            ..config
+        };
+        config.spin_up.software_kick = false;
+        let sensors = Sensors::open(Path::new("/"), &config, true).unwrap();
+        Channel::new(config, sensors, None, false)
+    }
+
+    #[test]
+    fn status_line_shows_the_simulated_tach() {
+        let mut sim = SimulatedRegisters::new(Product::Emc2301, SIMULATED_MAX_RPM);
+        emc2301::set_fan_setting(&mut sim, 0, 128).unwrap();
+        let rpm = emc2301::fan_rpm(&mut sim, 0).ok();
+        assert_eq!(
+            status_line("Cpu Temp: 55.00°C", 128, None, rpm),
+            "Cpu Temp: 55.00°C, Fan Speed: 128, Fan RPM: 2509"
+        );
+    }
+
+    #[test]
+    fn status_line_shows_the_target_and_a_stopped_fan() {
+        let mut sim = SimulatedRegisters::new(Product::Emc2301, SIMULATED_MAX_RPM);
+        let rpm = emc2301::fan_rpm(&mut sim, 0).ok();
+        assert_eq!(
+            status_line("Cpu Temp: 65.00°C", 0, Some(0), rpm),
+            "Cpu Temp: 65.00°C, Fan Speed: 0, Target RPM: 0, Fan RPM: 0"
+        );
+    }
+
+    #[test]
+    fn status_line_without_tach() {
+        assert_eq!(
+            status_line("Cpu Temp: 41.50°C", 25, None, None),
+            "Cpu Temp: 41.50°C, Fan Speed: 25, Fan RPM: unknown"
+        );
+    }
+
+    #[tokio::test]
+    async fn alert_triggers_a_poll_handling_the_fault() {
+        let tree = TempTree::new("alert_poll");
+        let sensor = tree.file("temp", "50000");
+        let mut channels = vec![channel(FanConfig::default(), sensor)];
+        let mut driver = simulated_driver();
+        poll(&mut driver, &mut channels, &mut BTreeMap::new())
+            .await
+            .unwrap();
+        assert!(channels[0].last_speed < MAX_SPEED as u8);
+
+        let (alert_tx, mut alerts) = mpsc::unbounded_channel();
+        let mut line = SimulatedAlert::default();
+        line.watch(Box::new(move || {
+            let _ = alert_tx.send(());
+        }))
+        .unwrap();
+        let regs = driver.registers().unwrap();
+        regs.write(emc2301::FAN_STATUS, 0x01).unwrap();
+        regs.write(emc2301::FAN_STALL_STATUS, 0x01).unwrap();
+        line.trigger();
+        assert_eq!(alerts.recv().await, Some(()));
+        poll(&mut driver, &mut channels, &mut BTreeMap::new())
+            .await
+            .unwrap();
+        // The stall is kicked at full speed
+        assert_eq!(channels[0].faults.attempts(), 1);
+        assert_eq!(channels[0].last_speed, MAX_SPEED as u8);
+        let regs = driver.registers().unwrap();
+        assert_eq!(emc2301::fan_setting(regs, 0).unwrap(), MAX_SPEED as u8);
+    }
+
+    /// Ramp ticks until the software ramp of `channel` reaches `last_speed`
+    fn ramp_ticks(channel: &mut Channel) -> u32 {
+        let mut ticks = 0;
+        while channel.speed != channel.last_speed {
+            channel.speed = channel.slew(RAMP_PERIOD_MS);
+            ticks += 1;
+        }
+        ticks
+    }
+
+    #[test]
+    fn slow_software_ramps_keep_their_rate() {
+        for (rate, ticks) in [(1, 157), (2, 79), (3, 53), (10, 16)] {
+            let config = FanConfig {
+                ramp_rate: rate,
+                ..FanConfig::default()
+            };
+            let mut channel = channel(config, PathBuf::from("temp"));
+            // 100 counts, 39% of full speed, in 39 s at 1%/s
+            (channel.speed, channel.last_speed) = (50, 150);
+            assert_eq!(ramp_ticks(&mut channel), ticks, "at {rate}%/s");
+            (channel.speed, channel.last_speed) = (150, 50);
+            assert_eq!(ramp_ticks(&mut channel), ticks, "down at {rate}%/s");
+        }
+    }
+
+    #[test]
+    fn software_ramp_skips_to_full_speed_and_stops() {
+        let config = FanConfig {
+            ramp_rate: 1,
+            ..FanConfig::default()
+        };
+        let mut channel = channel(config, PathBuf::from("temp"));
+        (channel.speed, channel.last_speed) = (50, MAX_SPEED as u8);
+        assert_eq!(channel.slew(RAMP_PERIOD_MS), MAX_SPEED as u8);
+        (channel.speed, channel.last_speed) = (50, 0);
+        assert_eq!(channel.slew(RAMP_PERIOD_MS), 0);
+    }
+
+    #[test]
+    fn locked_chip_keeps_its_tach_config() {
+        let mut sim = SimulatedRegisters::new(Product::Emc2301, SIMULATED_MAX_RPM);
+        // rpm mode with RANGE 2x and 5 edges, locked by an earlier run
+        let fan_config1 = emc2301::fan_register(0, emc2301::FAN_CONFIG1);
+        sim.write(fan_config1, 0xAB).unwrap();
+        emc2301::lock(&mut sim).unwrap();
+        let config = FanConfig {
+            mode: ControlMode::Rpm,
+            ..FanConfig::default()
+        };
+        let mut channels = vec![channel(config, PathBuf::from("temp"))];
+        configure(&mut sim, &mut channels, false, true).unwrap();
+        let locked = TachConfig::from_fan_config1(0xAB);
+        assert_eq!(channels[0].tach, locked);
+        assert_ne!(TachConfig::new(2, 500), locked);
+        // rpm mode targets are counted in the locked range
+        let mut driver = Emc230x::new(Box::new(sim), Some(Product::Emc2301), I2C_BUS, I2C_SLA);
+        channels[0].set_fan(&mut driver, 128).unwrap();
+        let regs = driver.registers().unwrap();
+        let rpm = emc2301::fan_rpm(regs, 0).unwrap();
+        assert!((2490..=2510).contains(&rpm), "{rpm} RPM");
+    }
+
+    #[test]
+    fn stalls_are_reported_unless_the_fan_has_no_tach() {
+        let valid_tach = emc2301::fan_register(0, emc2301::VALID_TACH_COUNT);
+        for (pulses_per_rev, expected) in [(None, 0xFE), (Some(0), 0xFF)] {
+            let mut sim = SimulatedRegisters::new(Product::Emc2301, SIMULATED_MAX_RPM);
+            let config = FanConfig {
+                pulses_per_rev,
+                ..FanConfig::default()
+            };
+            let mut channels = vec![channel(config, PathBuf::from("temp"))];
+            configure(&mut sim, &mut channels, false, false).unwrap();
+            assert_eq!(sim.read(valid_tach).unwrap(), expected);
+        }
+        // The default fan stalls below the slowest speed its range measures
+        let tach = TachConfig::new(2, FanConfig::default().slowest_rpm());
+        assert_eq!(tach.rpm(emc2301::tach_from_bytes(0xFE, 0)), 483);
+    }
+
+    #[tokio::test]
+    async fn lifting_the_chip_ramp_is_no_reset() {
+        let tree = TempTree::new("ramp_lift");
+        let sensor = tree.file("temp", "60000");
+        let config = FanConfig {
+            mode: ControlMode::Rpm,
+            ramp_rate: 10,
+            ..FanConfig::default()
+        };
+        let mut channels = vec![channel(config, sensor.clone())];
+        let sim = SimulatedRegisters::new(Product::Emc2301, SIMULATED_MAX_RPM);
+        let power_cycle = sim.power_cycle_flag();
+        let mut driver = Emc230x::new(Box::new(sim), Some(Product::Emc2301), I2C_BUS, I2C_SLA);
+        let regs = driver.registers().unwrap();
+        let written = configure(regs, &mut channels, false, false).unwrap();
+        let mut applied = read_applied(regs, &written).unwrap();
+        poll(&mut driver, &mut channels, &mut applied)
+            .await
+            .unwrap();
+        applied = read_applied(driver.registers().unwrap(), &written).unwrap();
+
+        // Full speed for several periods, then back down
+        for temp in ["80000", "80000", "80000", "80000", "60000", "60000"] {
+            std::fs::write(&sensor, temp).unwrap();
+            poll(&mut driver, &mut channels, &mut applied)
+                .await
+                .unwrap();
+            let regs = driver.registers().unwrap();
+            let changes = check_controller(regs, &applied, &channels, 1).unwrap();
+            assert!(changes.is_empty(), "reset reported: {changes:?}");
+            assert_eq!(channels[0].ramp_lifted, temp == "80000");
+        }
+
+        power_cycle.store(true, Ordering::Relaxed);
+        let regs = driver.registers().unwrap();
+        assert!(!check_controller(regs, &applied, &channels, 1)
+            .unwrap()
+            .is_empty());
+    }
 }
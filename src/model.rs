@@ -0,0 +1,54 @@
+//! Presets of common fans, picked with `model` in the fan settings
+
+use serde::Deserialize;
+
+/// A fan with known tach and speed characteristics
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+pub enum FanModel {
+    /// Noctua NF-A4x10 5V PWM, 40 mm 4-wire
+    #[serde(rename = "noctua-nf-a4x10-5v-pwm")]
+    NoctuaNfA4x10Pwm,
+    /// A 30 mm fan with only power and ground, switched by the PWM output
+    #[serde(rename = "generic-2-wire-30mm")]
+    Generic2Wire30mm,
+}
+
+/// Tach and speed characteristics of a fan
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Preset {
+    /// Tach pulses per revolution, 0 without a tach wire
+    pub pulses_per_rev: u8,
+    /// Lowest drive the fan starts and keeps running at, percent
+    pub min_drive: u8,
+    /// Fan speed, in RPM, at full drive
+    pub max_rpm: u32,
+}
+
+impl Default for Preset {
+    /// A typical 4-wire PWM fan
+    fn default() -> Self {
+        Self {
+            pulses_per_rev: 2,
+            min_drive: 10,
+            max_rpm: 5000,
+        }
+    }
+}
+
+impl FanModel {
+    /// The characteristics of the fan
+    pub fn preset(self) -> Preset {
+        match self {
+            FanModel::NoctuaNfA4x10Pwm => Preset {
+                pulses_per_rev: 2,
+                min_drive: 20,
+                max_rpm: 5000,
+            },
+            FanModel::Generic2Wire30mm => Preset {
+                pulses_per_rev: 0,
+                min_drive: 40,
+                max_rpm: 8000,
+            },
+        }
+    }
+}
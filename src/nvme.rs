@@ -0,0 +1,306 @@
+//! NVMe drive temperature, from the kernel hwmon sensor or the SMART / Health log
+
+use std::{
+    error::Error,
+    fs::{self, File},
+    io,
+    os::fd::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+use crate::{curve::Curve, hwmon};
+
+/// Directory of the NVMe controllers under the sysfs root
+const NVME_CLASS: &str = "class/nvme";
+/// `tempN_label` of the composite temperature in the kernel hwmon sensor
+const COMPOSITE_LABEL: &str = "Composite";
+/// `NVME_IOCTL_ADMIN_CMD`, `_IOWR('N', 0x41, struct nvme_admin_cmd)`
+const NVME_IOCTL_ADMIN_CMD: u64 = 0xC048_4E41;
+/// Get Log Page admin opcode
+const GET_LOG_PAGE: u8 = 0x02;
+/// SMART / Health Information log identifier
+const SMART_LOG: u8 = 0x02;
+/// Length of the SMART / Health Information log
+const SMART_LOG_LEN: usize = 512;
+/// Namespace identifier of the controller wide log
+const ALL_NAMESPACES: u32 = 0xFFFF_FFFF;
+/// Zero degrees Celsius in Kelvin, the unit of the log temperatures
+const ZERO_CELSIUS: f32 = 273.15;
+/// Temperature of the simulated drive in Kelvin
+const SIMULATED_KELVIN: u16 = 318;
+
+/// NVMe drive temperature settings of a fan
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NvmeConfig {
+    /// Controller character device, named like its sysfs class entry
+    pub device: PathBuf,
+    /// Fan speed vs drive temperature curve
+    pub curve: Curve,
+}
+
+impl Default for NvmeConfig {
+    fn default() -> Self {
+        Self {
+            device: PathBuf::from("/dev/nvme0"),
+            curve: Curve {
+                off_temp: 35.0,
+                min_temp: 40.0,
+                max_temp: 70.0,
+                low_speed: 10,
+            },
+        }
+    }
+}
+
+/// The admin commands of an NVMe controller
+pub trait NvmeAdmin: Send {
+    /// Read the log page `log_id` into `buf`
+    fn get_log_page(&mut self, log_id: u8, buf: &mut [u8]) -> io::Result<()>;
+}
+
+/// `struct nvme_admin_cmd` of the kernel NVMe ioctl interface
+#[repr(C)]
+#[derive(Default)]
+struct AdminCmd {
+    opcode: u8,
+    flags: u8,
+    rsvd1: u16,
+    nsid: u32,
+    cdw2: u32,
+    cdw3: u32,
+    metadata: u64,
+    addr: u64,
+    metadata_len: u32,
+    data_len: u32,
+    cdw10: u32,
+    cdw11: u32,
+    cdw12: u32,
+    cdw13: u32,
+    cdw14: u32,
+    cdw15: u32,
+    timeout_ms: u32,
+    result: u32,
+}
+
+/// An NVMe controller character device
+pub struct NvmeDevice {
+    file: File,
+}
+
+impl NvmeDevice {
+    /// Open the controller, admin commands need root
+    pub fn open(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let file =
+            File::open(path).map_err(|err| format!("Unable to open {}: {err}", path.display()))?;
+        Ok(Self { file })
+    }
+}
+
+impl NvmeAdmin for NvmeDevice {
+    fn get_log_page(&mut self, log_id: u8, buf: &mut [u8]) -> io::Result<()> {
+        let dwords = (buf.len() / 4) as u32;
+        let mut cmd = AdminCmd {
+            opcode: GET_LOG_PAGE,
+            nsid: ALL_NAMESPACES,
+            addr: buf.as_mut_ptr() as u64,
+            data_len: buf.len() as u32,
+            // Number of dwords, 0 based, above the log identifier
+            cdw10: u32::from(log_id) | (dwords - 1) << 16,
+            ..AdminCmd::default()
+        };
+        // SAFETY: cmd matches the kernel layout and addr points at data_len writable bytes
+        let ret = unsafe {
+            libc::ioctl(
+                self.file.as_raw_fd(),
+                NVME_IOCTL_ADMIN_CMD as _,
+                &mut cmd as *mut AdminCmd,
+            )
+        };
+        match ret {
+            0 => Ok(()),
+            ret if ret < 0 => Err(io::Error::last_os_error()),
+            status => Err(io::Error::other(format!("NVMe status {status:#x}"))),
+        }
+    }
+}
+
+/// A controller answering with a SMART log at a fixed temperature
+pub struct SimulatedNvme {
+    kelvin: u16,
+}
+
+impl NvmeAdmin for SimulatedNvme {
+    fn get_log_page(&mut self, log_id: u8, buf: &mut [u8]) -> io::Result<()> {
+        if log_id != SMART_LOG || buf.len() < 3 {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        buf.fill(0);
+        buf[1..3].copy_from_slice(&self.kelvin.to_le_bytes());
+        Ok(())
+    }
+}
+
+/// The Composite Temperature of a SMART / Health log in degrees Celsius
+pub fn composite_temp(log: &[u8]) -> Option<f32> {
+    let kelvin = u16::from_le_bytes([*log.get(1)?, *log.get(2)?]);
+    // 0 when the controller does not report it
+    (kelvin > 0).then(|| f32::from(kelvin) - ZERO_CELSIUS)
+}
+
+/// Where the drive temperature is read
+enum Source {
+    /// `tempN_input` of the kernel hwmon sensor
+    Hwmon(PathBuf),
+    /// The SMART / Health log through the admin commands
+    Admin(Box<dyn NvmeAdmin>),
+}
+
+/// The temperature of an NVMe drive and the fan speed it asks for
+pub struct Nvme {
+    source: Source,
+    pub curve: Curve,
+}
+
+impl Nvme {
+    /// Use the hwmon sensor of the controller under the sysfs `root`, or its admin commands.
+    /// Simulated, the admin commands are answered in memory.
+    pub fn open(root: &Path, config: &NvmeConfig, simulate: bool) -> Result<Self, Box<dyn Error>> {
+        let source = match find_hwmon(root, &config.device) {
+            Some(input) => {
+                println!("NVMe temperature: {}", input.display());
+                Source::Hwmon(input)
+            }
+            None if simulate => Source::Admin(Box::new(SimulatedNvme {
+                kelvin: SIMULATED_KELVIN,
+            })),
+            None => {
+                println!("NVMe temperature: SMART log of {}", config.device.display());
+                Source::Admin(Box::new(NvmeDevice::open(&config.device)?))
+            }
+        };
+        Ok(Self {
+            source,
+            curve: config.curve.clone(),
+        })
+    }
+
+    /// The drive temperature in degrees Celsius
+    pub fn read(&mut self) -> Result<f32, String> {
+        match &mut self.source {
+            Source::Hwmon(input) => {
+                let text = fs::read_to_string(&*input).map_err(|err| {
+                    format!("Missing NVMe temperature in {}: {err}", input.display())
+                })?;
+                text.trim()
+                    .parse::<f32>()
+                    .map(|temp| temp / 1000.0)
+                    .map_err(|err| format!("Bad NVMe temperature in {}: {err}", input.display()))
+            }
+            Source::Admin(admin) => {
+                let mut log = [0; SMART_LOG_LEN];
+                admin
+                    .get_log_page(SMART_LOG, &mut log)
+                    .map_err(|err| format!("Unable to read the NVMe SMART log: {err}"))?;
+                composite_temp(&log).ok_or_else(|| "NVMe temperature not reported".to_string())
+            }
+        }
+    }
+}
+
+/// The composite temperature input of the controller hwmon sensor, when the kernel has one
+fn find_hwmon(root: &Path, device: &Path) -> Option<PathBuf> {
+    let name = device.file_name()?;
+    let controller = root.join(NVME_CLASS).join(name);
+    let mut devices: Vec<PathBuf> = fs::read_dir(&controller)
+        .ok()?
+        .flatten()
+        .filter(|entry| entry.file_name().to_string_lossy().starts_with("hwmon"))
+        .map(|entry| entry.path())
+        .collect();
+    devices.sort();
+    devices
+        .iter()
+        .find_map(|device| hwmon::find_temp(device, Some(COMPOSITE_LABEL)).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempTree;
+
+    /// Admin commands failing like a controller gone from the bus
+    struct FailingAdmin;
+
+    impl NvmeAdmin for FailingAdmin {
+        fn get_log_page(&mut self, _log_id: u8, _buf: &mut [u8]) -> io::Result<()> {
+            Err(io::Error::from_raw_os_error(libc::ENODEV))
+        }
+    }
+
+    fn nvme(admin: Box<dyn NvmeAdmin>) -> Nvme {
+        Nvme {
+            source: Source::Admin(admin),
+            curve: NvmeConfig::default().curve,
+        }
+    }
+
+    #[test]
+    fn admin_cmd_matches_the_ioctl_size() {
+        assert_eq!(size_of::<AdminCmd>(), 0x48);
+        // The size field of the ioctl number
+        assert_eq!((NVME_IOCTL_ADMIN_CMD >> 16) & 0x3FFF, 0x48);
+    }
+
+    #[test]
+    fn composite_temp_decodes_kelvin() {
+        let mut log = [0; SMART_LOG_LEN];
+        log[1..3].copy_from_slice(&318u16.to_le_bytes());
+        assert_eq!(composite_temp(&log), Some(318.0 - ZERO_CELSIUS));
+        log[1..3].copy_from_slice(&0u16.to_le_bytes());
+        assert_eq!(composite_temp(&log), None);
+        assert_eq!(composite_temp(&[0, 0x3E]), None);
+    }
+
+    #[test]
+    fn smart_log_is_read_through_the_admin_commands() {
+        let mut drive = nvme(Box::new(SimulatedNvme { kelvin: 330 }));
+        assert_eq!(drive.read().unwrap(), 330.0 - ZERO_CELSIUS);
+        let mut drive = nvme(Box::new(FailingAdmin));
+        let err = drive.read().unwrap_err();
+        assert!(
+            err.starts_with("Unable to read the NVMe SMART log"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn hwmon_sensor_is_preferred() {
+        let tree = TempTree::new("nvme_hwmon");
+        tree.file("class/nvme/nvme0/hwmon2/temp1_label", "Sensor 1\n");
+        tree.file("class/nvme/nvme0/hwmon3/temp1_label", "Sensor 1\n");
+        tree.file("class/nvme/nvme0/hwmon3/temp2_label", "Composite\n");
+        let input = tree.file("class/nvme/nvme0/hwmon3/temp2_input", "51850\n");
+        assert_eq!(
+            find_hwmon(tree.path(), Path::new("/dev/nvme0")),
+            Some(input)
+        );
+        assert_eq!(find_hwmon(tree.path(), Path::new("/dev/nvme1")), None);
+
+        let mut drive = Nvme::open(tree.path(), &NvmeConfig::default(), true).unwrap();
+        assert!(matches!(drive.source, Source::Hwmon(_)));
+        assert_eq!(drive.read().unwrap(), 51.85);
+        // Without the hwmon sensor the simulated admin commands answer
+        let config = NvmeConfig {
+            device: PathBuf::from("/dev/nvme1"),
+            ..NvmeConfig::default()
+        };
+        let mut drive = Nvme::open(tree.path(), &config, true).unwrap();
+        assert_eq!(
+            drive.read().unwrap(),
+            f32::from(SIMULATED_KELVIN) - ZERO_CELSIUS
+        );
+    }
+}
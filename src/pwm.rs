@@ -0,0 +1,103 @@
+//! Fans driven straight from a Raspberry Pi PWM output
+
+use std::{collections::BTreeMap, error::Error, fmt};
+
+use rppal::{
+    gpio::{Gpio, OutputPin},
+    pwm::{Channel, Polarity, Pwm},
+};
+
+use crate::{
+    config::{Config, Driver, FanConfig},
+    driver::FanDriver,
+    emc2301::PwmPolarity,
+};
+
+/// Default frequency of a hardware PWM output, the 4-wire fan standard
+const HARDWARE_HZ: f64 = 25000.0;
+/// Default frequency of a software PWM output, low enough for its timing to hold
+const SOFTWARE_HZ: f64 = 100.0;
+
+/// The PWM output of one fan
+enum Output {
+    /// A PWM channel of the SoC, inverting in hardware
+    Hardware(Pwm),
+    /// A GPIO toggled by a thread, inverting the duty
+    Software {
+        pin: OutputPin,
+        hz: f64,
+        inverted: bool,
+    },
+}
+
+impl Output {
+    /// Open the output of a fan, stopped until its first duty
+    fn open(driver: Driver, config: &FanConfig) -> Result<Self, Box<dyn Error>> {
+        let inverted = config.pwm_polarity == PwmPolarity::Inverted;
+        if let Some(pin) = config.gpio_pin.filter(|_| driver == Driver::Gpio) {
+            let hz = config.pwm_hz.unwrap_or(SOFTWARE_HZ);
+            let mut pin = Gpio::new()
+                .and_then(|gpio| gpio.get(pin))
+                .map_err(|err| format!("Unable to open GPIO {pin} for the fan: {err}"))?
+                .into_output_low();
+            pin.set_pwm_frequency(hz, if inverted { 1.0 } else { 0.0 })?;
+            return Ok(Output::Software { pin, hz, inverted });
+        }
+        let channel = match config.pwm_channel {
+            0 => Channel::Pwm0,
+            _ => Channel::Pwm1,
+        };
+        let polarity = if inverted {
+            Polarity::Inverse
+        } else {
+            Polarity::Normal
+        };
+        let hz = config.pwm_hz.unwrap_or(HARDWARE_HZ);
+        let pwm = Pwm::with_frequency(channel, hz, 0.0, polarity, true)
+            .map_err(|err| format!("Unable to open PWM channel {channel}: {err}"))?;
+        Ok(Output::Hardware(pwm))
+    }
+
+    /// Set the duty cycle, 0 to 255
+    fn set_duty(&mut self, duty: u8) -> Result<(), Box<dyn Error>> {
+        let cycle = f64::from(duty) / f64::from(u8::MAX);
+        match self {
+            Output::Hardware(pwm) => pwm.set_duty_cycle(cycle)?,
+            Output::Software { pin, hz, inverted } => {
+                pin.set_pwm_frequency(*hz, if *inverted { 1.0 - cycle } else { cycle })?
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Fans on hardware PWM channels or software PWM GPIOs
+pub struct PwmFans {
+    outputs: BTreeMap<u8, Output>,
+}
+
+impl PwmFans {
+    /// Open the output of every configured fan
+    pub fn open(config: &Config) -> Result<Self, Box<dyn Error>> {
+        let mut outputs = BTreeMap::new();
+        for fan in config.channels() {
+            outputs.insert(fan.fan(), Output::open(config.fan.driver, fan)?);
+        }
+        Ok(Self { outputs })
+    }
+}
+
+impl fmt::Display for PwmFans {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "the PWM outputs")
+    }
+}
+
+impl FanDriver for PwmFans {
+    fn set_duty(&mut self, fan: u8, duty: u8) -> Result<(), Box<dyn Error>> {
+        self.outputs
+            .get_mut(&fan)
+            .ok_or_else(|| format!("fan {} has no PWM output", fan + 1))?
+            .set_duty(duty)
+    }
+}
@@ -0,0 +1,49 @@
+//! Helpers shared by the unit tests
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process,
+};
+
+/// A directory tree under the temporary directory, removed when dropped
+pub struct TempTree {
+    root: PathBuf,
+}
+
+impl TempTree {
+    /// An empty tree, `name` keeps the trees of concurrent tests apart
+    pub fn new(name: &str) -> Self {
+        let root = env::temp_dir().join(format!("cm4_fan_control-{}-{name}", process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        Self { root }
+    }
+
+    /// The root of the tree
+    pub fn path(&self) -> &Path {
+        &self.root
+    }
+
+    /// Write `contents` to `path` in the tree, creating its directories
+    pub fn file(&self, path: &str, contents: &str) -> PathBuf {
+        let path = self.root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    /// Link `path` in the tree to `target`, creating its directories
+    pub fn link(&self, target: &str, path: &str) -> PathBuf {
+        let path = self.root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::os::unix::fs::symlink(target, &path).unwrap();
+        path
+    }
+}
+
+impl Drop for TempTree {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
@@ -0,0 +1,521 @@
+//! Temperature inputs of a fan, thermal zones, hwmon, I2C and 1-Wire sensors combined into the
+//! one reading the curve follows
+
+use std::{
+    error::Error,
+    fmt, fs,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+
+use crate::{
+    ambient::{AmbientSensor, Chip},
+    config::FanConfig,
+    hwmon, w1,
+};
+
+/// Directory of the thermal zones under the sysfs root
+const THERMAL_CLASS: &str = "class/thermal";
+/// The zone followed when neither `sensor` nor `zones` is set
+const DEFAULT_ZONE: &str = "thermal_zone0";
+/// Label of the default zone and of `sensor`
+const CPU_LABEL: &str = "Cpu";
+/// I2C bus of the sensors, on the 40 pin header
+const SENSOR_BUS: u8 = 1;
+/// Reads in a row a zone may miss, its last temperature standing in, before it is left out
+const MAX_MISSED_READS: u32 = 3;
+
+/// One temperature input of a fan, a file, a thermal zone found by type, a hwmon sensor
+/// found by chip name and label, an I2C sensor or a 1-Wire probe
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ZoneConfig {
+    /// Temperature file in millidegrees Celsius
+    pub path: Option<PathBuf>,
+    /// `type` attribute of the thermal zone, looked up under the sysfs root
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+    /// `name` attribute of the hwmon device, looked up under the sysfs root
+    pub hwmon: Option<String>,
+    /// `tempN_label` of the hwmon sensor, `temp1` when unset
+    pub hwmon_label: Option<String>,
+    /// The device the hwmon device belongs to, like `nvme0`, when several share its name
+    pub hwmon_device: Option<String>,
+    /// I2C sensor chip
+    pub i2c: Option<Chip>,
+    /// I2C bus of the sensor
+    pub bus: u8,
+    /// I2C address of the sensor, the chip default when unset
+    pub address: Option<u16>,
+    /// ID of the DS18B20 1-Wire probe, like `28-0316a2794aff`
+    pub w1: Option<String>,
+    /// Name in the logs, the type, the hwmon name, device and label, the I2C chip and address,
+    /// the probe ID or the path when unset
+    pub label: Option<String>,
+    /// Share of the zone in the weighted mean
+    pub weight: f32,
+}
+
+impl ZoneConfig {
+    /// Name of the zone in the logs
+    pub fn label(&self) -> String {
+        if let Some(label) = self
+            .label
+            .as_ref()
+            .or(self.kind.as_ref())
+            .or(self.w1.as_ref())
+        {
+            return label.clone();
+        }
+        if let Some(chip) = self.i2c {
+            return format!("{chip} {:#04x}", self.address(chip));
+        }
+        match (&self.hwmon, &self.path) {
+            (Some(name), _) => [
+                Some(name),
+                self.hwmon_device.as_ref(),
+                self.hwmon_label.as_ref(),
+            ]
+            .into_iter()
+            .flatten()
+            .map(String::as_str)
+            .collect::<Vec<_>>()
+            .join(" "),
+            (None, Some(path)) => path.display().to_string(),
+            (None, None) => String::new(),
+        }
+    }
+
+    /// I2C address of the sensor
+    pub fn address(&self, chip: Chip) -> u16 {
+        self.address.unwrap_or_else(|| chip.default_address())
+    }
+}
+
+impl Default for ZoneConfig {
+    fn default() -> Self {
+        Self {
+            path: None,
+            kind: None,
+            hwmon: None,
+            hwmon_label: None,
+            hwmon_device: None,
+            i2c: None,
+            bus: SENSOR_BUS,
+            address: None,
+            w1: None,
+            label: None,
+            weight: 1.0,
+        }
+    }
+}
+
+/// How the zone temperatures combine into the one the curve follows
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Aggregation {
+    /// The hottest zone
+    #[default]
+    Max,
+    /// The mean of the zones
+    Mean,
+    /// The mean of the zones by their weight
+    Weighted,
+    /// The zone with this label
+    Zone(String),
+}
+
+impl fmt::Display for Aggregation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Aggregation::Max => write!(f, "Max"),
+            Aggregation::Mean => write!(f, "Mean"),
+            Aggregation::Weighted => write!(f, "Weighted"),
+            Aggregation::Zone(label) => write!(f, "{label}"),
+        }
+    }
+}
+
+/// The thermal zone whose `type` attribute is `kind`, under the sysfs `root`
+pub fn find(root: &Path, kind: &str) -> Result<PathBuf, Box<dyn Error>> {
+    let class = root.join(THERMAL_CLASS);
+    let entries =
+        fs::read_dir(&class).map_err(|err| format!("Unable to list {}: {err}", class.display()))?;
+    let mut zones: Vec<PathBuf> = entries
+        .flatten()
+        .filter(|entry| {
+            entry
+                .file_name()
+                .to_string_lossy()
+                .starts_with("thermal_zone")
+        })
+        .map(|entry| entry.path())
+        .collect();
+    zones.sort();
+    zones
+        .into_iter()
+        .find(|zone| fs::read_to_string(zone.join("type")).is_ok_and(|found| found.trim() == kind))
+        .ok_or_else(|| format!("No thermal zone of type {kind} in {}", class.display()).into())
+}
+
+/// The temperature of a sensor in degrees Celsius
+async fn read_temp(sensor: &Path) -> Result<f32, std::io::Error> {
+    let temp_unparsed = tokio::fs::read_to_string(sensor).await?;
+    Ok(temp_unparsed.trim().parse::<f32>().unwrap_or(45000.0) / 1000.0)
+}
+
+/// Where a zone is read
+enum Source {
+    /// A file in millidegrees Celsius
+    File(PathBuf),
+    /// An I2C sensor
+    I2c(AmbientSensor),
+    /// The sysfs directory of a 1-Wire probe
+    W1(PathBuf),
+}
+
+/// A temperature input found
+struct Zone {
+    label: String,
+    source: Source,
+    weight: f32,
+    /// The last temperature read
+    last: Option<f32>,
+    /// Reads failed in a row
+    missed: u32,
+}
+
+impl Zone {
+    /// The temperature in degrees Celsius
+    async fn read(&mut self) -> Result<f32, String> {
+        match &mut self.source {
+            Source::File(path) => read_temp(path).await.map_err(|err| {
+                format!(
+                    "Missing {} temperature in {}: {err}",
+                    self.label,
+                    path.display()
+                )
+            }),
+            Source::I2c(sensor) => sensor.read().await,
+            Source::W1(device) => w1::read(device).await,
+        }
+    }
+
+    /// The temperature, or the last one while at most `MAX_MISSED_READS` reads in a row
+    /// failed, and why the read failed
+    async fn sample(&mut self) -> (Option<f32>, Option<String>) {
+        match self.read().await {
+            Ok(temp) => {
+                self.last = Some(temp);
+                self.missed = 0;
+                (Some(temp), None)
+            }
+            Err(err) => {
+                self.missed += 1;
+                match self.last.filter(|_| self.missed <= MAX_MISSED_READS) {
+                    Some(last) => (
+                        Some(last),
+                        Some(format!(
+                            "{err}, keeping the last temperature ({} of {MAX_MISSED_READS})",
+                            self.missed
+                        )),
+                    ),
+                    None => (None, Some(err)),
+                }
+            }
+        }
+    }
+}
+
+/// The temperature of every zone and the one they combine into
+pub struct Temperature {
+    /// What the curve follows
+    pub value: f32,
+    /// Label and temperature of each zone, `None` when it has none
+    readings: Vec<(String, Option<f32>)>,
+    aggregation: Aggregation,
+    /// Why the zones could not be read
+    pub errors: Vec<String>,
+}
+
+impl fmt::Display for Temperature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, (label, temp)) in self.readings.iter().enumerate() {
+            if index > 0 {
+                write!(f, ", ")?;
+            }
+            match temp {
+                Some(temp) => write!(f, "{label} Temp: {temp:.2}°C")?,
+                None => write!(f, "{label} Temp: unknown")?,
+            }
+        }
+        // A single zone or the zone followed is already shown
+        if self.readings.len() > 1 && !matches!(self.aggregation, Aggregation::Zone(_)) {
+            write!(f, ", {} Temp: {:.2}°C", self.aggregation, self.value)?;
+        }
+        Ok(())
+    }
+}
+
+/// The temperature inputs of a fan
+pub struct Sensors {
+    zones: Vec<Zone>,
+    aggregation: Aggregation,
+}
+
+impl Sensors {
+    /// Find the zones of a fan, thermal zone 0 under the sysfs `root` when none is set.
+    /// Simulated, the I2C sensors answer from memory.
+    pub fn open(root: &Path, config: &FanConfig, simulate: bool) -> Result<Self, Box<dyn Error>> {
+        let mut zones = Vec::new();
+        for zone in &config.zones {
+            let source = match (&zone.path, &zone.kind, &zone.hwmon, zone.i2c) {
+                (Some(path), ..) => Source::File(path.clone()),
+                (None, Some(kind), ..) => Source::File(find(root, kind)?.join("temp")),
+                (None, None, Some(name), _) => {
+                    let device = hwmon::find(root, name, zone.hwmon_device.as_deref())?;
+                    Source::File(hwmon::find_temp(&device, zone.hwmon_label.as_deref())?)
+                }
+                (None, None, None, Some(chip)) => {
+                    let address = zone.address(chip);
+                    Source::I2c(AmbientSensor::open(chip, zone.bus, address, simulate)?)
+                }
+                (None, None, None, None) => match &zone.w1 {
+                    Some(id) => Source::W1(w1::device(root, id)),
+                    None => {
+                        return Err(
+                            "a zone needs a path, a type, a hwmon, an i2c or a w1 sensor".into(),
+                        )
+                    }
+                },
+            };
+            zones.push(Zone {
+                label: zone.label(),
+                source,
+                weight: zone.weight,
+                last: None,
+                missed: 0,
+            });
+        }
+        if zones.is_empty() {
+            let path = config
+                .sensor
+                .clone()
+                .unwrap_or_else(|| root.join(THERMAL_CLASS).join(DEFAULT_ZONE).join("temp"));
+            zones.push(Zone {
+                label: CPU_LABEL.to_string(),
+                source: Source::File(path),
+                weight: 1.0,
+                last: None,
+                missed: 0,
+            });
+        }
+        Ok(Self {
+            zones,
+            aggregation: config.aggregation.clone(),
+        })
+    }
+
+    /// Read every zone and combine the ones with a temperature, read or standing in for a
+    /// missed read, failing only when the aggregation has none left
+    pub async fn read(&mut self) -> Result<Temperature, String> {
+        let mut temps = Vec::new();
+        let mut errors = Vec::new();
+        for zone in &mut self.zones {
+            let (temp, err) = zone.sample().await;
+            temps.push(temp);
+            errors.push(err);
+        }
+        // (temperature, weight) of the zones with one
+        let read = || {
+            temps
+                .iter()
+                .zip(&self.zones)
+                .filter_map(|(temp, zone)| Some(((*temp)?, zone.weight)))
+        };
+        let value = match &self.aggregation {
+            Aggregation::Zone(label) => {
+                let index = self
+                    .zones
+                    .iter()
+                    .position(|zone| &zone.label == label)
+                    .ok_or_else(|| format!("No zone labeled {label}"))?;
+                temps[index].ok_or_else(|| errors[index].clone().unwrap_or_default())?
+            }
+            _ if read().next().is_none() => {
+                let errors: Vec<String> = errors.into_iter().flatten().collect();
+                return Err(errors.join(", "));
+            }
+            Aggregation::Max => read().map(|(temp, _)| temp).fold(f32::MIN, f32::max),
+            Aggregation::Mean => read().map(|(temp, _)| temp).sum::<f32>() / read().count() as f32,
+            Aggregation::Weighted => {
+                let total: f32 = read().map(|(_, weight)| weight).sum();
+                read().map(|(temp, weight)| temp * weight).sum::<f32>() / total
+            }
+        };
+        let readings = self
+            .zones
+            .iter()
+            .zip(temps)
+            .map(|(zone, temp)| (zone.label.clone(), temp))
+            .collect();
+        Ok(Temperature {
+            value,
+            readings,
+            aggregation: self.aggregation.clone(),
+            errors: errors.into_iter().flatten().collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempTree;
+
+    #[test]
+    fn find_matches_the_zone_type() {
+        let tree = TempTree::new("thermal_find");
+        tree.file("class/thermal/thermal_zone0/type", "cpu-thermal\n");
+        tree.file("class/thermal/thermal_zone1/type", "gpu-thermal\n");
+        tree.file("class/thermal/cooling_device0/type", "gpu-thermal\n");
+        let class = tree.path().join(THERMAL_CLASS);
+        assert_eq!(
+            find(tree.path(), "gpu-thermal").unwrap(),
+            class.join("thermal_zone1")
+        );
+        let err = find(tree.path(), "nvme").unwrap_err().to_string();
+        assert_eq!(
+            err,
+            format!("No thermal zone of type nvme in {}", class.display())
+        );
+        assert!(find(&tree.path().join("missing"), "cpu-thermal").is_err());
+    }
+
+    /// Sensors of zones `cpu` at 60°C with weight 3, `gpu` at 40°C and `ssd` not readable
+    fn open_zones(tree: &TempTree, aggregation: Aggregation, zones: &[&str]) -> Sensors {
+        tree.file("cpu", "60000\n");
+        tree.file("gpu", "40000\n");
+        let zones = zones
+            .iter()
+            .map(|&label| ZoneConfig {
+                path: Some(tree.path().join(label)),
+                label: Some(label.to_string()),
+                weight: if label == "cpu" { 3.0 } else { 1.0 },
+                ..ZoneConfig::default()
+            })
+            .collect();
+        let config = FanConfig {
+            zones,
+            aggregation,
+            ..FanConfig::default()
+        };
+        Sensors::open(tree.path(), &config, true).unwrap()
+    }
+
+    #[tokio::test]
+    async fn aggregations_combine_the_zones() {
+        let tree = TempTree::new("thermal_combine");
+        for (aggregation, value) in [
+            (Aggregation::Max, 60.0),
+            (Aggregation::Mean, 50.0),
+            (Aggregation::Weighted, 55.0),
+            (Aggregation::Zone("gpu".into()), 40.0),
+        ] {
+            let mut sensors = open_zones(&tree, aggregation, &["cpu", "gpu"]);
+            let temp = sensors.read().await.unwrap();
+            assert_eq!(temp.value, value);
+            assert!(temp.errors.is_empty());
+        }
+        let mut sensors = open_zones(&tree, Aggregation::Mean, &["cpu", "gpu"]);
+        assert_eq!(
+            sensors.read().await.unwrap().to_string(),
+            "cpu Temp: 60.00°C, gpu Temp: 40.00°C, Mean Temp: 50.00°C"
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_zones_are_left_out() {
+        let tree = TempTree::new("thermal_failed");
+        for (aggregation, value) in [
+            (Aggregation::Max, 40.0),
+            (Aggregation::Mean, 40.0),
+            (Aggregation::Weighted, 40.0),
+            (Aggregation::Zone("gpu".into()), 40.0),
+        ] {
+            let mut sensors = open_zones(&tree, aggregation, &["ssd", "gpu"]);
+            let temp = sensors.read().await.unwrap();
+            assert_eq!(temp.value, value);
+            assert_eq!(temp.errors.len(), 1);
+            assert!(temp.errors[0].starts_with("Missing ssd temperature"));
+        }
+        let mut sensors = open_zones(&tree, Aggregation::Max, &["ssd", "gpu"]);
+        assert_eq!(
+            sensors.read().await.unwrap().to_string(),
+            "ssd Temp: unknown, gpu Temp: 40.00°C, Max Temp: 40.00°C"
+        );
+    }
+
+    #[tokio::test]
+    async fn reading_fails_without_a_zone_left() {
+        let tree = TempTree::new("thermal_none");
+        let mut sensors = open_zones(&tree, Aggregation::Max, &["ssd"]);
+        assert!(sensors.read().await.is_err());
+        // The labeled zone fails whatever the others read
+        let mut sensors = open_zones(&tree, Aggregation::Zone("ssd".into()), &["ssd", "cpu"]);
+        let err = sensors.read().await.err().unwrap();
+        assert!(err.starts_with("Missing ssd temperature"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn hwmon_zones_read_the_labeled_sensor() {
+        let tree = TempTree::new("thermal_hwmon");
+        for (hwmon, drive, temp) in [("hwmon2", "nvme1", "51850"), ("hwmon10", "nvme0", "38850")] {
+            tree.file(&format!("class/hwmon/{hwmon}/name"), "nvme\n");
+            tree.link(drive, &format!("class/hwmon/{hwmon}/device"));
+            tree.file(&format!("class/hwmon/{hwmon}/temp1_label"), "Composite\n");
+            tree.file(&format!("class/hwmon/{hwmon}/temp1_input"), temp);
+            tree.file(&format!("class/hwmon/{hwmon}/temp2_label"), "Sensor 1\n");
+            tree.file(&format!("class/hwmon/{hwmon}/temp2_input"), "60000");
+        }
+        let zone = |device: Option<&str>| ZoneConfig {
+            hwmon: Some("nvme".into()),
+            hwmon_label: Some("Composite".into()),
+            hwmon_device: device.map(String::from),
+            ..ZoneConfig::default()
+        };
+        let config = |zones| FanConfig {
+            zones,
+            ..FanConfig::default()
+        };
+        let mut sensors =
+            Sensors::open(tree.path(), &config(vec![zone(Some("nvme0"))]), true).unwrap();
+        assert_eq!(
+            sensors.read().await.unwrap().to_string(),
+            "nvme nvme0 Composite Temp: 38.85°C"
+        );
+        assert!(Sensors::open(tree.path(), &config(vec![zone(None)]), true).is_err());
+    }
+
+    #[tokio::test]
+    async fn missed_reads_keep_the_last_temperature() {
+        let tree = TempTree::new("thermal_missed");
+        let mut sensors = open_zones(&tree, Aggregation::Max, &["cpu"]);
+        assert_eq!(sensors.read().await.unwrap().value, 60.0);
+        std::fs::remove_file(tree.path().join("cpu")).unwrap();
+        for missed in 1..=MAX_MISSED_READS {
+            let temp = sensors.read().await.unwrap();
+            assert_eq!(temp.value, 60.0);
+            let suffix = format!("keeping the last temperature ({missed} of {MAX_MISSED_READS})");
+            assert!(temp.errors[0].ends_with(&suffix), "{}", temp.errors[0]);
+        }
+        let err = sensors.read().await.err().unwrap();
+        assert!(err.starts_with("Missing cpu temperature"), "{err}");
+        // A read starts the count again
+        tree.file("cpu", "50000\n");
+        assert_eq!(sensors.read().await.unwrap().value, 50.0);
+        std::fs::remove_file(tree.path().join("cpu")).unwrap();
+        assert_eq!(sensors.read().await.unwrap().value, 50.0);
+    }
+}
@@ -0,0 +1,117 @@
+//! DS18B20 1-Wire probes, read through the w1_therm driver
+
+use std::path::{Path, PathBuf};
+
+/// Directory of the 1-Wire devices under the sysfs root
+const W1_DEVICES: &str = "bus/w1/devices";
+/// Temperature register value at power-on, read when no conversion ran
+const POWER_ON_MILLIDEGREES: i32 = 85000;
+/// Start of the DS18B20 IDs, its 1-Wire family code
+pub const DS18B20_FAMILY: &str = "28-";
+
+/// The sysfs directory of the probe with ID `id`, like `28-0316a2794aff`
+pub fn device(root: &Path, id: &str) -> PathBuf {
+    root.join(W1_DEVICES).join(id)
+}
+
+/// Degrees Celsius in `w1_slave`, a CRC line ending with YES then a line ending with `t=`
+fn parse_w1_slave(text: &str) -> Result<i32, String> {
+    let mut lines = text.lines();
+    let crc = lines.next().unwrap_or_default();
+    if !crc.trim_end().ends_with("YES") {
+        return Err(format!("bad CRC: {}", crc.trim()));
+    }
+    lines
+        .next()
+        .and_then(|line| line.split_once("t="))
+        .ok_or("no temperature")?
+        .1
+        .trim()
+        .parse()
+        .map_err(|err| format!("bad temperature: {err}"))
+}
+
+/// The temperature of the probe in `device`, from `w1_slave` with its CRC, or from
+/// `temperature` on drivers without it
+pub async fn read(device: &Path) -> Result<f32, String> {
+    let id = device.file_name().unwrap_or_default().to_string_lossy();
+    let millidegrees = match tokio::fs::read_to_string(device.join("w1_slave")).await {
+        Ok(text) => parse_w1_slave(&text),
+        Err(_) => {
+            let path = device.join("temperature");
+            let text = tokio::fs::read_to_string(&path)
+                .await
+                .map_err(|err| format!("Missing {id} temperature in {}: {err}", path.display()))?;
+            text.trim()
+                .parse()
+                .map_err(|err| format!("bad temperature: {err}"))
+        }
+    }
+    .map_err(|err| format!("Probe {id}: {err}"))?;
+    if millidegrees == POWER_ON_MILLIDEGREES {
+        return Err(format!(
+            "Probe {id} reads its 85°C power-on value, the conversion failed"
+        ));
+    }
+    Ok(millidegrees as f32 / 1000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempTree;
+
+    const GOOD_CRC: &str = "72 01 4b 46 7f ff 0e 10 57 : crc=57 YES\n";
+    const BAD_CRC: &str = "72 01 4b 46 7f ff 0e 10 57 : crc=00 NO\n";
+
+    #[test]
+    fn w1_slave_needs_a_good_crc() {
+        let reading = "72 01 4b 46 7f ff 0e 10 57 t=23125\n";
+        assert_eq!(parse_w1_slave(&format!("{GOOD_CRC}{reading}")), Ok(23125));
+        assert_eq!(
+            parse_w1_slave(&format!("{BAD_CRC}{reading}")),
+            Err("bad CRC: 72 01 4b 46 7f ff 0e 10 57 : crc=00 NO".into())
+        );
+        assert_eq!(parse_w1_slave(GOOD_CRC), Err("no temperature".into()));
+        // Below zero
+        let reading = "5e ff 4b 46 7f ff 0c 10 b2 t=-10125\n";
+        assert_eq!(parse_w1_slave(&format!("{GOOD_CRC}{reading}")), Ok(-10125));
+    }
+
+    #[tokio::test]
+    async fn read_checks_the_probe() {
+        let tree = TempTree::new("w1_read");
+        let probe = device(tree.path(), "28-0316a2794aff");
+        tree.file(
+            "bus/w1/devices/28-0316a2794aff/w1_slave",
+            &format!("{GOOD_CRC}72 01 4b 46 7f ff 0e 10 57 t=23125\n"),
+        );
+        assert_eq!(read(&probe).await, Ok(23.125));
+        tree.file(
+            "bus/w1/devices/28-0316a2794aff/w1_slave",
+            &format!("{GOOD_CRC}50 05 4b 46 7f ff 0c 10 1c t=85000\n"),
+        );
+        let err = read(&probe).await.unwrap_err();
+        assert!(err.contains("85°C power-on value"), "{err}");
+        tree.file(
+            "bus/w1/devices/28-0316a2794aff/w1_slave",
+            &format!("{BAD_CRC}72 01 4b 46 7f ff 0e 10 57 t=23125\n"),
+        );
+        let err = read(&probe).await.unwrap_err();
+        assert!(err.starts_with("Probe 28-0316a2794aff: bad CRC"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn read_falls_back_to_temperature() {
+        let tree = TempTree::new("w1_temperature");
+        let probe = device(tree.path(), "28-0316a2794aff");
+        tree.file("bus/w1/devices/28-0316a2794aff/temperature", "-5500\n");
+        assert_eq!(read(&probe).await, Ok(-5.5));
+        let missing = device(tree.path(), "28-000000000000");
+        let err = read(&missing).await.unwrap_err();
+        assert!(
+            err.starts_with("Missing 28-000000000000 temperature"),
+            "{err}"
+        );
+    }
+}